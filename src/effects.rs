@@ -0,0 +1,1210 @@
+use crate::{
+    noise_maker::{osc, FreqType, WaveType, DEFAULT_SAMPLE_RATE},
+    sequencer::NoteDivision,
+    smoothed_param::SmoothedParam,
+};
+use enum_dispatch::enum_dispatch;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::{PI, TAU};
+
+#[enum_dispatch]
+pub trait Effect {
+    fn process(&mut self, sample: FreqType) -> FreqType;
+
+    /// Effects without per channel state only process the mid signal and keep the stereo width
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        let mid = self.process((left + right) / 2.0);
+        let side = (left - right) / 2.0;
+        (mid + side, mid - side)
+    }
+
+    /// Moves the cutoff of filters by `octaves` from the cutoff they were set to, used by LFOs
+    fn modulate_cutoff(&mut self, _octaves: FreqType) {}
+
+    /// Sets the cutoff of filters in Hz, used to sweep the filters of the master effects
+    fn set_cutoff(&mut self, _cutoff: FreqType) {}
+}
+
+#[enum_dispatch(Effect)]
+pub enum EffectType {
+    LowPassFilter,
+    Delay,
+    Chorus,
+    Distortion,
+    BiquadFilter,
+    HighPassFilter,
+    BandPassFilter,
+    Reverb,
+    RingModulator,
+    BitCrusher,
+    DcBlocker,
+    Compressor,
+    HaasWidener,
+    Phaser,
+    Flanger,
+    Oversampler,
+    TranceGate,
+}
+
+/// One-pole low-pass filter
+pub struct LowPassFilter {
+    /// Smoothed so the cutoff can be changed while playing without zipper noise
+    cutoff: SmoothedParam,
+    /// Set by `modulate_cutoff`
+    modulation_octaves: FreqType,
+    sample_rate: FreqType,
+    alpha: FreqType,
+    previous: FreqType,
+}
+
+impl LowPassFilter {
+    pub fn new(cutoff: FreqType, sample_rate: u32) -> Self {
+        let cutoff = cutoff.clamp(1.0, sample_rate as FreqType / 2.0);
+        let mut filter = Self {
+            cutoff: SmoothedParam::new(cutoff, SmoothedParam::DEFAULT_TIME_SECS, sample_rate),
+            modulation_octaves: 0.0,
+            sample_rate: sample_rate as FreqType,
+            alpha: 0.0,
+            previous: 0.0,
+        };
+        filter.update_alpha(cutoff);
+        filter
+    }
+
+    pub fn cutoff(&self) -> FreqType {
+        self.cutoff.target()
+    }
+
+    fn update_alpha(&mut self, cutoff: FreqType) {
+        let rc = 1.0 / (TAU * cutoff.clamp(1.0, self.sample_rate / 2.0));
+        let dt = 1.0 / self.sample_rate;
+        self.alpha = dt / (rc + dt);
+    }
+}
+
+impl Effect for LowPassFilter {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        if self.cutoff.is_smoothing() {
+            let cutoff = self.cutoff.next_sample();
+            self.update_alpha(cutoff * 2.0_f64.powf(self.modulation_octaves));
+        }
+        self.previous += self.alpha * (sample - self.previous);
+        self.previous
+    }
+
+    fn modulate_cutoff(&mut self, octaves: FreqType) {
+        self.modulation_octaves = octaves;
+        self.update_alpha(self.cutoff.value() * 2.0_f64.powf(octaves));
+    }
+
+    fn set_cutoff(&mut self, cutoff: FreqType) {
+        self.cutoff
+            .set_target(cutoff.clamp(1.0, self.sample_rate / 2.0));
+    }
+}
+
+/// Echo effect feeding the delayed signal back into a ring buffer
+pub struct Delay {
+    buffer: Vec<FreqType>,
+    position: usize,
+    feedback: FreqType,
+    mix: FreqType,
+}
+
+impl Delay {
+    pub fn new(delay_secs: FreqType, feedback: FreqType, mix: FreqType, sample_rate: u32) -> Self {
+        let len = ((delay_secs * sample_rate as FreqType) as usize).max(1);
+        Self {
+            buffer: vec![0.0; len],
+            position: 0,
+            // Feedback of 1.0 or more would never decay
+            feedback: feedback.clamp(0.0, 0.99),
+            mix: mix.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        let delayed = self.buffer[self.position];
+        self.buffer[self.position] = sample + delayed * self.feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        sample * (1.0 - self.mix) + delayed * self.mix
+    }
+}
+
+/// Ring buffer that can be read at fractional delays
+struct DelayLine {
+    buffer: Vec<FreqType>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(2)],
+            position: 0,
+        }
+    }
+
+    fn push(&mut self, sample: FreqType) {
+        self.buffer[self.position] = sample;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+
+    /// Linearly interpolates between the two samples around the delay to avoid zipper noise
+    fn read(&self, delay_samples: FreqType) -> FreqType {
+        let len = self.buffer.len();
+        let delay_samples = delay_samples.clamp(0.0, (len - 2) as FreqType);
+        let whole = delay_samples as usize;
+        let frac = delay_samples.fract();
+        // The last pushed sample is at a delay of 0
+        let newer = self.buffer[(self.position + len - 1 - whole) % len];
+        let older = self.buffer[(self.position + len - 2 - whole) % len];
+        newer * (1.0 - frac) + older * frac
+    }
+}
+
+const CHORUS_VOICES: usize = 3;
+const CHORUS_MIN_DELAY_SECS: FreqType = 0.005;
+const CHORUS_MAX_DELAY_SECS: FreqType = 0.025;
+
+/// Mixes the dry signal with a few delayed copies whose delay times are modulated by LFOs
+pub struct Chorus {
+    delay_line: DelayLine,
+    rate: FreqType,
+    depth: FreqType,
+    mix: FreqType,
+    sample_rate: FreqType,
+    num_sample: usize,
+}
+
+impl Chorus {
+    pub fn new(rate: FreqType, depth: FreqType, mix: FreqType, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as FreqType;
+        Self {
+            delay_line: DelayLine::new((CHORUS_MAX_DELAY_SECS * sample_rate) as usize + 2),
+            rate: rate.max(0.01),
+            depth: depth.clamp(0.0, 1.0),
+            mix: mix.clamp(0.0, 1.0),
+            sample_rate,
+            num_sample: 0,
+        }
+    }
+}
+
+impl Effect for Chorus {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        self.delay_line.push(sample);
+        self.num_sample = self.num_sample.wrapping_add(1);
+        let dt = self.num_sample as FreqType / self.sample_rate;
+
+        let center = (CHORUS_MIN_DELAY_SECS + CHORUS_MAX_DELAY_SECS) / 2.0;
+        let range = (CHORUS_MAX_DELAY_SECS - CHORUS_MIN_DELAY_SECS) / 2.0;
+        let wet = (0..CHORUS_VOICES)
+            .map(|voice| {
+                // Spread the LFOs evenly over one period
+                let offset = voice as FreqType / (CHORUS_VOICES as FreqType * self.rate);
+                let lfo = osc(
+                    dt + offset,
+                    self.rate,
+                    &WaveType::Sine,
+                    0.0,
+                    self.sample_rate as u32,
+                );
+                let delay = center + range * self.depth * lfo;
+                self.delay_line.read(delay * self.sample_rate)
+            })
+            .sum::<FreqType>()
+            / CHORUS_VOICES as FreqType;
+
+        sample * (1.0 - self.mix) + wet * self.mix
+    }
+}
+
+/// Soft clipping waveshaper
+pub struct Distortion {
+    drive: FreqType,
+}
+
+impl Distortion {
+    pub fn new(drive: FreqType) -> Self {
+        Self {
+            drive: drive.clamp(1.0, 50.0),
+        }
+    }
+}
+
+impl Effect for Distortion {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        // Normalized so a full scale input stays at full scale
+        (self.drive * sample).tanh() / self.drive.tanh()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum BiquadMode {
+    Low,
+    High,
+    /// Constant 0 dB gain at the center frequency
+    Band,
+}
+
+/// Resonant low-pass, high-pass or band-pass filter, see `BiquadMode`, using the coefficients from
+/// Robert Bristow-Johnson's audio EQ cookbook https://www.w3.org/TR/audio-eq-cookbook/
+#[derive(Clone, Debug)]
+pub struct BiquadFilter {
+    mode: BiquadMode,
+    cutoff: FreqType,
+    q: FreqType,
+    sample_rate: FreqType,
+    b0: FreqType,
+    b1: FreqType,
+    b2: FreqType,
+    a1: FreqType,
+    a2: FreqType,
+    x1: FreqType,
+    x2: FreqType,
+    y1: FreqType,
+    y2: FreqType,
+}
+
+impl BiquadFilter {
+    /// Low-pass filter
+    pub fn new(cutoff: FreqType, q: FreqType, sample_rate: u32) -> Self {
+        Self::with_mode(BiquadMode::Low, cutoff, q, sample_rate)
+    }
+
+    /// Low-pass, high-pass or band-pass filter depending on `mode`
+    pub fn with_mode(mode: BiquadMode, cutoff: FreqType, q: FreqType, sample_rate: u32) -> Self {
+        let mut filter = Self {
+            mode,
+            cutoff,
+            q,
+            sample_rate: sample_rate as FreqType,
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        };
+        filter.set_params(cutoff, q);
+        filter
+    }
+
+    pub fn cutoff(&self) -> FreqType {
+        self.cutoff
+    }
+
+    pub fn q(&self) -> FreqType {
+        self.q
+    }
+
+    /// Only recomputes the coefficients so the filter can be swept without clicks
+    pub fn set_params(&mut self, cutoff: FreqType, q: FreqType) {
+        self.cutoff = cutoff.clamp(1.0, self.sample_rate * 0.49);
+        self.q = q.max(0.01);
+        self.update_coefficients(self.cutoff);
+    }
+
+    fn update_coefficients(&mut self, cutoff: FreqType) {
+        let cutoff = cutoff.clamp(1.0, self.sample_rate * 0.49);
+        let w0 = TAU * cutoff / self.sample_rate;
+        let alpha = w0.sin() / (2.0 * self.q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha;
+        match self.mode {
+            BiquadMode::Low => {
+                self.b1 = (1.0 - cos_w0) / a0;
+                self.b0 = self.b1 / 2.0;
+                self.b2 = self.b0;
+            }
+            BiquadMode::High => {
+                self.b1 = -(1.0 + cos_w0) / a0;
+                self.b0 = -self.b1 / 2.0;
+                self.b2 = self.b0;
+            }
+            BiquadMode::Band => {
+                self.b0 = alpha / a0;
+                self.b1 = 0.0;
+                self.b2 = -self.b0;
+            }
+        }
+        self.a1 = -2.0 * cos_w0 / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+}
+
+impl Effect for BiquadFilter {
+    fn process(&mut self, x: FreqType) -> FreqType {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+
+    fn modulate_cutoff(&mut self, octaves: FreqType) {
+        self.update_coefficients(self.cutoff * 2.0_f64.powf(octaves));
+    }
+
+    fn set_cutoff(&mut self, cutoff: FreqType) {
+        self.set_params(cutoff, self.q);
+    }
+}
+
+/// Biquad high-pass filter, removes DC offset and rumble
+pub struct HighPassFilter(BiquadFilter);
+
+impl HighPassFilter {
+    pub fn new(cutoff: FreqType, q: FreqType, sample_rate: u32) -> Self {
+        Self(BiquadFilter::with_mode(
+            BiquadMode::High,
+            cutoff,
+            q,
+            sample_rate,
+        ))
+    }
+
+    pub fn cutoff(&self) -> FreqType {
+        self.0.cutoff()
+    }
+
+    pub fn q(&self) -> FreqType {
+        self.0.q()
+    }
+
+    pub fn set_params(&mut self, cutoff: FreqType, q: FreqType) {
+        self.0.set_params(cutoff, q);
+    }
+}
+
+impl Effect for HighPassFilter {
+    fn process(&mut self, x: FreqType) -> FreqType {
+        self.0.process(x)
+    }
+
+    fn modulate_cutoff(&mut self, octaves: FreqType) {
+        self.0.modulate_cutoff(octaves);
+    }
+
+    fn set_cutoff(&mut self, cutoff: FreqType) {
+        self.0.set_cutoff(cutoff);
+    }
+}
+
+/// Biquad band-pass filter centered on the cutoff, a higher `q` makes the band narrower
+pub struct BandPassFilter(BiquadFilter);
+
+impl BandPassFilter {
+    pub fn new(cutoff: FreqType, q: FreqType, sample_rate: u32) -> Self {
+        Self(BiquadFilter::with_mode(
+            BiquadMode::Band,
+            cutoff,
+            q,
+            sample_rate,
+        ))
+    }
+
+    pub fn cutoff(&self) -> FreqType {
+        self.0.cutoff()
+    }
+
+    pub fn q(&self) -> FreqType {
+        self.0.q()
+    }
+
+    pub fn set_params(&mut self, cutoff: FreqType, q: FreqType) {
+        self.0.set_params(cutoff, q);
+    }
+}
+
+impl Effect for BandPassFilter {
+    fn process(&mut self, x: FreqType) -> FreqType {
+        self.0.process(x)
+    }
+
+    fn modulate_cutoff(&mut self, octaves: FreqType) {
+        self.0.modulate_cutoff(octaves);
+    }
+
+    fn set_cutoff(&mut self, cutoff: FreqType) {
+        self.0.set_cutoff(cutoff);
+    }
+}
+
+/// Lowpass feedback comb filter used by the reverb
+struct CombFilter {
+    buffer: Vec<FreqType>,
+    position: usize,
+    filter_store: FreqType,
+}
+
+impl CombFilter {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            position: 0,
+            filter_store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: FreqType, feedback: FreqType, damp: FreqType) -> FreqType {
+        let output = self.buffer[self.position];
+        self.filter_store = output * (1.0 - damp) + self.filter_store * damp;
+        self.buffer[self.position] = input + self.filter_store * feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct AllpassFilter {
+    buffer: Vec<FreqType>,
+    position: usize,
+}
+
+impl AllpassFilter {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            position: 0,
+        }
+    }
+
+    fn process(&mut self, input: FreqType) -> FreqType {
+        let buffered = self.buffer[self.position];
+        self.buffer[self.position] = input + buffered * 0.5;
+        self.position = (self.position + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// Delay lengths from Freeverb, tuned for 44.1kHz
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+/// Offset of the right channel delays, decorrelates the channels
+const STEREO_SPREAD: usize = 23;
+const FREEVERB_SAMPLE_RATE: FreqType = 44100.0;
+const FIXED_GAIN: FreqType = 0.015;
+const SCALE_WET: FreqType = 3.0;
+const SCALE_DRY: FreqType = 2.0;
+
+/// Parallel comb filters feeding series allpass filters for each channel
+struct ReverbChannel {
+    combs: Vec<CombFilter>,
+    allpasses: Vec<AllpassFilter>,
+}
+
+impl ReverbChannel {
+    fn new(spread: usize, sample_rate: FreqType) -> Self {
+        let scale =
+            |len: usize| ((len + spread) as FreqType * sample_rate / FREEVERB_SAMPLE_RATE) as usize;
+        Self {
+            combs: COMB_LENGTHS
+                .iter()
+                .map(|len| CombFilter::new(scale(*len)))
+                .collect(),
+            allpasses: ALLPASS_LENGTHS
+                .iter()
+                .map(|len| AllpassFilter::new(scale(*len)))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: FreqType, feedback: FreqType, damp: FreqType) -> FreqType {
+        let combed = self
+            .combs
+            .iter_mut()
+            .map(|comb| comb.process(input, feedback, damp))
+            .sum::<FreqType>();
+        self.allpasses
+            .iter_mut()
+            .fold(combed, |sample, allpass| allpass.process(sample))
+    }
+}
+
+/// Freeverb style reverb, see https://ccrma.stanford.edu/~jos/pasp/Freeverb.html
+pub struct Reverb {
+    left: ReverbChannel,
+    right: ReverbChannel,
+    feedback: FreqType,
+    damp: FreqType,
+    wet: FreqType,
+    dry: FreqType,
+}
+
+impl Reverb {
+    /// All parameters go from 0.0 to 1.0
+    pub fn new(
+        room_size: FreqType,
+        damping: FreqType,
+        wet: FreqType,
+        dry: FreqType,
+        sample_rate: u32,
+    ) -> Self {
+        let sample_rate = sample_rate as FreqType;
+        Self {
+            left: ReverbChannel::new(0, sample_rate),
+            right: ReverbChannel::new(STEREO_SPREAD, sample_rate),
+            feedback: room_size.clamp(0.0, 1.0) * 0.28 + 0.7,
+            damp: damping.clamp(0.0, 1.0) * 0.4,
+            wet: wet.clamp(0.0, 1.0) * SCALE_WET,
+            dry: dry.clamp(0.0, 1.0) * SCALE_DRY,
+        }
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        let wet = self
+            .left
+            .process(sample * FIXED_GAIN, self.feedback, self.damp);
+        wet * self.wet + sample * self.dry
+    }
+
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        let input = (left + right) * FIXED_GAIN;
+        let wet_left = self.left.process(input, self.feedback, self.damp);
+        let wet_right = self.right.process(input, self.feedback, self.damp);
+        (
+            wet_left * self.wet + left * self.dry,
+            wet_right * self.wet + right * self.dry,
+        )
+    }
+}
+
+/// Multiplies the signal by a sine carrier, giving metallic inharmonic tones
+pub struct RingModulator {
+    carrier_hz: FreqType,
+    sample_rate: FreqType,
+    /// Phase of the carrier in periods
+    phase: FreqType,
+}
+
+impl RingModulator {
+    pub fn new(carrier_hz: FreqType, sample_rate: u32) -> Self {
+        Self {
+            carrier_hz,
+            sample_rate: sample_rate as FreqType,
+            phase: 0.0,
+        }
+    }
+
+    pub fn carrier_hz(&self) -> FreqType {
+        self.carrier_hz
+    }
+
+    pub fn set_carrier_hz(&mut self, carrier_hz: FreqType) {
+        self.carrier_hz = carrier_hz;
+    }
+
+    fn next_carrier(&mut self) -> FreqType {
+        let carrier = (TAU * self.phase).sin();
+        self.phase = (self.phase + self.carrier_hz / self.sample_rate).fract();
+        carrier
+    }
+}
+
+impl Effect for RingModulator {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        sample * self.next_carrier()
+    }
+
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        let carrier = self.next_carrier();
+        (left * carrier, right * carrier)
+    }
+}
+
+/// Lo-fi effect reducing the bit depth and holding samples to lower the sample rate
+pub struct BitCrusher {
+    /// Number of quantization levels between -1 and 1
+    levels: FreqType,
+    downsample: u32,
+    counter: u32,
+    held: (FreqType, FreqType),
+}
+
+impl BitCrusher {
+    pub fn new(bits: u32, downsample: u32) -> Self {
+        Self {
+            levels: 2.0_f64.powi(bits.clamp(1, 24) as i32),
+            downsample: downsample.max(1),
+            counter: 0,
+            held: (0.0, 0.0),
+        }
+    }
+
+    fn quantize(&self, sample: FreqType) -> FreqType {
+        let steps = self.levels - 1.0;
+        ((sample.clamp(-1.0, 1.0) + 1.0) / 2.0 * steps).round() / steps * 2.0 - 1.0
+    }
+}
+
+impl Effect for BitCrusher {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        self.process_stereo(sample, sample).0
+    }
+
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        if self.counter == 0 {
+            self.held = (self.quantize(left), self.quantize(right));
+        }
+        self.counter = (self.counter + 1) % self.downsample;
+        self.held
+    }
+}
+
+/// Removes the DC offset with a one-pole high-pass filter, `y[n] = x[n] - x[n-1] + R * y[n-1]`
+#[derive(Default)]
+pub struct DcBlocker {
+    previous_input: (FreqType, FreqType),
+    previous_output: (FreqType, FreqType),
+}
+
+impl DcBlocker {
+    const R: FreqType = 0.995;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Effect for DcBlocker {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        self.process_stereo(sample, sample).0
+    }
+
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        let output = (
+            left - self.previous_input.0 + Self::R * self.previous_output.0,
+            right - self.previous_input.1 + Self::R * self.previous_output.1,
+        );
+        self.previous_input = (left, right);
+        self.previous_output = output;
+        output
+    }
+}
+
+/// Reduces the gain of the signal above the threshold, to even out chords coming in and out
+pub struct Compressor {
+    threshold_db: FreqType,
+    ratio: FreqType,
+    attack: FreqType,
+    release: FreqType,
+    /// Smoothed amplitude of the signal
+    envelope: FreqType,
+}
+
+impl Compressor {
+    /// A ratio of `FreqType::INFINITY` makes it a limiter
+    pub fn new(
+        threshold_db: FreqType,
+        ratio: FreqType,
+        attack_ms: FreqType,
+        release_ms: FreqType,
+        sample_rate: u32,
+    ) -> Self {
+        let sample_rate = sample_rate as FreqType;
+        Self {
+            threshold_db,
+            ratio: ratio.max(1.0),
+            attack: Self::smoothing(attack_ms, sample_rate),
+            release: Self::smoothing(release_ms, sample_rate),
+            envelope: 0.0,
+        }
+    }
+
+    /// One-pole smoothing coefficient reaching ~63% of a step after `ms`
+    fn smoothing(ms: FreqType, sample_rate: FreqType) -> FreqType {
+        1.0 - (-1000.0 / (ms.max(0.001) * sample_rate)).exp()
+    }
+
+    /// Follows the level of `sample` and returns the gain to apply
+    fn gain(&mut self, sample: FreqType) -> FreqType {
+        let level = sample.abs();
+        let smoothing = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        self.envelope += (level - self.envelope) * smoothing;
+        let over_db = 20.0 * self.envelope.log10() - self.threshold_db;
+        if over_db <= 0.0 {
+            return 1.0;
+        }
+        let reduction_db = over_db - over_db / self.ratio;
+        10.0_f64.powf(-reduction_db / 20.0)
+    }
+}
+
+impl Effect for Compressor {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        sample * self.gain(sample)
+    }
+
+    /// Both channels get the same gain so the stereo image doesn't move
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        let gain = self.gain(left.abs().max(right.abs()));
+        (left * gain, right * gain)
+    }
+}
+
+/// Widens the stereo image by delaying the right channel a few milliseconds behind the left
+pub struct HaasWidener {
+    delay: DelayLine,
+    delay_samples: FreqType,
+}
+
+impl HaasWidener {
+    /// Short enough that the comb filtering stays mild when summed to mono
+    pub const DEFAULT_DELAY_MS: FreqType = 8.0;
+    const MIN_DELAY_MS: FreqType = 5.0;
+    const MAX_DELAY_MS: FreqType = 30.0;
+
+    /// `delay_ms` is clamped between 5 and 30ms, the range where the delay is heard as width and not an echo
+    pub fn new(delay_ms: FreqType, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as FreqType;
+        let delay_samples =
+            delay_ms.clamp(Self::MIN_DELAY_MS, Self::MAX_DELAY_MS) / 1000.0 * sample_rate;
+        Self {
+            delay: DelayLine::new(delay_samples.ceil() as usize + 2),
+            delay_samples,
+        }
+    }
+}
+
+impl Effect for HaasWidener {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        self.process_stereo(sample, sample).0
+    }
+
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        self.delay.push(right);
+        (left, self.delay.read(self.delay_samples))
+    }
+}
+
+const PHASER_MIN_HZ: FreqType = 100.0;
+const PHASER_MAX_HZ: FreqType = 4000.0;
+
+/// First-order all-pass filter, shifts the phase around its break frequency without changing the level
+#[derive(Clone, Copy, Default)]
+struct PhaserStage {
+    previous_input: FreqType,
+    previous_output: FreqType,
+}
+
+impl PhaserStage {
+    fn process(&mut self, input: FreqType, coefficient: FreqType) -> FreqType {
+        let output = coefficient * input + self.previous_input - coefficient * self.previous_output;
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+/// Mixes the dry signal with a cascade of all-pass filters swept by an LFO, creating moving notches
+pub struct Phaser {
+    stages: Vec<PhaserStage>,
+    rate: FreqType,
+    depth: FreqType,
+    feedback: FreqType,
+    mix: FreqType,
+    sample_rate: FreqType,
+    num_sample: usize,
+    last_wet: FreqType,
+}
+
+impl Phaser {
+    /// Every pair of `stages` adds a notch, `depth` from 0.0 to 1.0 is the part of the sweep range used
+    pub fn new(
+        stages: usize,
+        rate: FreqType,
+        depth: FreqType,
+        feedback: FreqType,
+        mix: FreqType,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            stages: vec![PhaserStage::default(); stages.max(1)],
+            rate: rate.max(0.01),
+            depth: depth.clamp(0.0, 1.0),
+            // Feedback of 1.0 or more would never decay
+            feedback: feedback.clamp(-0.99, 0.99),
+            mix: mix.clamp(0.0, 1.0),
+            sample_rate: sample_rate as FreqType,
+            num_sample: 0,
+            last_wet: 0.0,
+        }
+    }
+}
+
+impl Effect for Phaser {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        self.num_sample = self.num_sample.wrapping_add(1);
+        let dt = self.num_sample as FreqType / self.sample_rate;
+        let lfo = osc(dt, self.rate, &WaveType::Sine, 0.0, self.sample_rate as u32);
+        // Swept exponentially so the notches move evenly to the ear
+        let sweep = (1.0 + self.depth * lfo) / 2.0;
+        let break_hz = PHASER_MIN_HZ * (PHASER_MAX_HZ / PHASER_MIN_HZ).powf(sweep);
+        let tan = (PI * break_hz / self.sample_rate).tan();
+        let coefficient = (tan - 1.0) / (tan + 1.0);
+
+        let input = sample + self.last_wet * self.feedback;
+        let wet = self
+            .stages
+            .iter_mut()
+            .fold(input, |signal, stage| stage.process(signal, coefficient));
+        self.last_wet = wet;
+        sample * (1.0 - self.mix) + wet * self.mix
+    }
+}
+
+const FLANGER_MIN_DELAY_SECS: FreqType = 0.0005;
+const FLANGER_MAX_DELAY_SECS: FreqType = 0.005;
+
+/// Mixes the dry signal with a copy delayed by a few milliseconds swept by an LFO,
+/// the feedback deepens the comb filter notches
+pub struct Flanger {
+    delay_line: DelayLine,
+    rate: FreqType,
+    depth: FreqType,
+    feedback: FreqType,
+    mix: FreqType,
+    sample_rate: FreqType,
+    num_sample: usize,
+    last_wet: FreqType,
+}
+
+impl Flanger {
+    /// `depth` from 0.0 to 1.0 is the part of the 0.5 to 5ms range swept, the delay stays at 0.5ms with 0.0
+    pub fn new(
+        rate: FreqType,
+        depth: FreqType,
+        feedback: FreqType,
+        mix: FreqType,
+        sample_rate: u32,
+    ) -> Self {
+        let sample_rate = sample_rate as FreqType;
+        Self {
+            delay_line: DelayLine::new((FLANGER_MAX_DELAY_SECS * sample_rate) as usize + 2),
+            rate: rate.max(0.01),
+            depth: depth.clamp(0.0, 1.0),
+            // Feedback of 1.0 or more would never decay
+            feedback: feedback.clamp(-0.99, 0.99),
+            mix: mix.clamp(0.0, 1.0),
+            sample_rate,
+            num_sample: 0,
+            last_wet: 0.0,
+        }
+    }
+}
+
+impl Effect for Flanger {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        self.delay_line.push(sample + self.last_wet * self.feedback);
+        self.num_sample = self.num_sample.wrapping_add(1);
+        let dt = self.num_sample as FreqType / self.sample_rate;
+
+        let lfo = osc(dt, self.rate, &WaveType::Sine, 0.0, self.sample_rate as u32);
+        let sweep = self.depth * (1.0 + lfo) / 2.0;
+        let delay =
+            FLANGER_MIN_DELAY_SECS + (FLANGER_MAX_DELAY_SECS - FLANGER_MIN_DELAY_SECS) * sweep;
+        let wet = self.delay_line.read(delay * self.sample_rate);
+        self.last_wet = wet;
+        sample * (1.0 - self.mix) + wet * self.mix
+    }
+}
+
+/// Q of the biquads of an 8th order Butterworth low-pass, flat up to the cutoff
+const BUTTERWORTH_8_Q: [FreqType; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+/// Steep low-pass at 90% of the Nyquist frequency of the original sample rate, run at the
+/// oversampled rate
+fn oversampling_filter(factor: usize) -> Vec<BiquadFilter> {
+    // Only the ratio of the cutoff to the sample rate matters, so any original rate works
+    let sample_rate = DEFAULT_SAMPLE_RATE * factor as u32;
+    let cutoff = 0.45 * DEFAULT_SAMPLE_RATE as FreqType;
+    BUTTERWORTH_8_Q
+        .iter()
+        .map(|q| BiquadFilter::new(cutoff, *q, sample_rate))
+        .collect()
+}
+
+/// Runs a nonlinear process at `factor` times the sample rate, so the harmonics it adds above the
+/// Nyquist frequency are filtered out instead of aliasing back as inharmonic tones
+pub struct Oversampler {
+    factor: usize,
+    process: Box<dyn FnMut(FreqType) -> FreqType + Send>,
+    /// Removes the images of the zero stuffed input
+    upsampling_filter: Vec<BiquadFilter>,
+    /// Removes the harmonics that can't be represented at the original sample rate
+    downsampling_filter: Vec<BiquadFilter>,
+}
+
+impl Oversampler {
+    pub const DEFAULT_FACTOR: usize = 4;
+
+    /// `process` is called `factor` times per sample, a factor of 1 doesn't filter anything
+    pub fn new(factor: usize, process: impl FnMut(FreqType) -> FreqType + Send + 'static) -> Self {
+        let factor = factor.max(1);
+        Self {
+            factor,
+            process: Box::new(process),
+            upsampling_filter: oversampling_filter(factor),
+            downsampling_filter: oversampling_filter(factor),
+        }
+    }
+}
+
+impl Effect for Oversampler {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        if self.factor == 1 {
+            return (self.process)(sample);
+        }
+        let mut output = 0.0;
+        for index in 0..self.factor {
+            // Zero stuffing, scaled so the filtered signal keeps the level of the input
+            let stuffed = if index == 0 {
+                sample * self.factor as FreqType
+            } else {
+                0.0
+            };
+            let upsampled = self
+                .upsampling_filter
+                .iter_mut()
+                .fold(stuffed, |sample, filter| filter.process(sample));
+            let processed = (self.process)(upsampled);
+            // Every filtered sample is needed for the filter state, only the last one is kept
+            output = self
+                .downsampling_filter
+                .iter_mut()
+                .fold(processed, |sample, filter| filter.process(sample));
+        }
+        output
+    }
+}
+
+/// Mutes and unmutes the signal rhythmically, every step of the pattern is a sixteenth note
+pub struct TranceGate {
+    pattern: Vec<bool>,
+    /// Length of a step in samples
+    step_samples: FreqType,
+    /// Ramps between open and closed so the gate edges don't click
+    gain: SmoothedParam,
+    num_sample: usize,
+}
+
+impl TranceGate {
+    /// `smoothing` is the time in seconds to open or close the gate, the signal passes through
+    /// unchanged with an empty pattern
+    pub fn new(bpm: FreqType, pattern: Vec<bool>, smoothing: FreqType, sample_rate: u32) -> Self {
+        let step_secs = 60.0 / bpm.max(1.0) / NoteDivision::Sixteenth.steps_per_beat();
+        let open = pattern.first().copied().unwrap_or(true);
+        Self {
+            pattern,
+            step_samples: step_secs * sample_rate as FreqType,
+            gain: SmoothedParam::new(if open { 1.0 } else { 0.0 }, smoothing, sample_rate),
+            num_sample: 0,
+        }
+    }
+
+    /// Advances by one sample and returns the gain to apply
+    fn gain(&mut self) -> FreqType {
+        if self.pattern.is_empty() {
+            return 1.0;
+        }
+        let step = (self.num_sample as FreqType / self.step_samples) as usize % self.pattern.len();
+        self.num_sample = self.num_sample.wrapping_add(1);
+        self.gain
+            .set_target(if self.pattern[step] { 1.0 } else { 0.0 });
+        self.gain.next_sample()
+    }
+}
+
+impl Effect for TranceGate {
+    fn process(&mut self, sample: FreqType) -> FreqType {
+        sample * self.gain()
+    }
+
+    /// Both channels are gated together
+    fn process_stereo(&mut self, left: FreqType, right: FreqType) -> (FreqType, FreqType) {
+        let gain = self.gain();
+        (left * gain, right * gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    #[test]
+    fn distortion_saturates_at_high_drive() {
+        let low = Distortion::new(1.0).process(0.5);
+        let high = Distortion::new(50.0).process(0.5);
+        // Only gently curved at low drive, close to the clip limit of 1.0 at high drive
+        assert!((low - 0.5).abs() < 0.15, "{}", low);
+        assert!(high > 0.99, "{}", high);
+        assert!(high > low);
+        assert!((Distortion::new(10.0).process(1.0) - 1.0).abs() < 1e-12);
+    }
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Feeds `input` for one second and returns the last output
+    fn settle(effect: &mut impl Effect, input: FreqType) -> FreqType {
+        let mut out = 0.0;
+        for _ in 0..SAMPLE_RATE {
+            out = effect.process(input);
+        }
+        out
+    }
+
+    /// Peak output of a sine once the effect settled
+    fn sine_peak(effect: &mut impl Effect, hertz: FreqType) -> FreqType {
+        let sine = |i: u32| (TAU * hertz * i as FreqType / SAMPLE_RATE as FreqType).sin();
+        for i in 0..SAMPLE_RATE {
+            effect.process(sine(i));
+        }
+        (SAMPLE_RATE..SAMPLE_RATE + SAMPLE_RATE / 10)
+            .map(|i| effect.process(sine(i)).abs())
+            .fold(0.0, FreqType::max)
+    }
+
+    #[test]
+    fn biquad_low_pass_passes_dc_and_cuts_highs() {
+        let mut filter = BiquadFilter::new(1000.0, 0.707, SAMPLE_RATE);
+        assert!((settle(&mut filter, 1.0) - 1.0).abs() < 1e-6);
+
+        let mut filter = BiquadFilter::new(1000.0, 0.707, SAMPLE_RATE);
+        // Two octaves and a half above the cutoff a 12 dB/octave slope is below -24 dB
+        let peak = sine_peak(&mut filter, 6000.0);
+        assert!(peak < 0.06, "{}", peak);
+    }
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let mut filter = HighPassFilter::new(100.0, 0.707, SAMPLE_RATE);
+        assert!(settle(&mut filter, 1.0).abs() < 1e-6);
+        let mut filter = BandPassFilter::new(1000.0, 0.707, SAMPLE_RATE);
+        assert!(settle(&mut filter, 1.0).abs() < 1e-6);
+        // Only the band around the center passes
+        let mut filter = BandPassFilter::new(1000.0, 0.707, SAMPLE_RATE);
+        assert!(sine_peak(&mut filter, 1000.0) > 0.95);
+    }
+
+    #[test]
+    fn one_bit_crusher_outputs_only_two_levels() {
+        let mut crusher = BitCrusher::new(1, 1);
+        let mut levels = (0..1000)
+            .map(|i| crusher.process((TAU * i as FreqType / 1000.0).sin()))
+            .collect::<Vec<_>>();
+        levels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        levels.dedup();
+        assert_eq!(levels, [-1.0, 1.0]);
+
+        let mut crusher = BitCrusher::new(16, 1);
+        for i in 0..1000 {
+            let sample = (TAU * i as FreqType / 1000.0).sin();
+            assert!((crusher.process(sample) - sample).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn dc_blocker_decays_a_constant_input_to_zero() {
+        let mut blocker = DcBlocker::new();
+        assert_eq!(blocker.process(1.0), 1.0);
+        assert!(settle(&mut blocker, 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn compressor_reduces_a_burst_after_the_attack() {
+        let mut compressor = Compressor::new(-20.0, 4.0, 5.0, 100.0, SAMPLE_RATE);
+        // Below the threshold of 0.1 nothing changes
+        for _ in 0..SAMPLE_RATE / 10 {
+            assert_eq!(compressor.process(0.05), 0.05);
+        }
+        let onset = compressor.process(1.0);
+        assert!(onset > 0.9, "{}", onset);
+        let frames_50ms = SAMPLE_RATE as usize / 20;
+        let settled = (0..frames_50ms)
+            .map(|_| compressor.process(1.0))
+            .last()
+            .unwrap();
+        // 20dB over the threshold comes out 5dB over it at a 4:1 ratio
+        let expected = 0.1 * 10.0_f64.powf(5.0 / 20.0);
+        assert!((settled - expected).abs() < 0.01, "{}", settled);
+    }
+
+    #[test]
+    fn flanger_without_depth_or_feedback_is_a_fixed_delay() {
+        let mut flanger = Flanger::new(1.0, 0.0, 0.0, 1.0, SAMPLE_RATE);
+        let input = (0..1000)
+            .map(|i| (i as FreqType * 0.37).sin())
+            .collect::<Vec<_>>();
+        let output = input
+            .iter()
+            .map(|sample| flanger.process(*sample))
+            .collect::<Vec<_>>();
+        // The minimum delay of 0.5ms is 24 samples at 48kHz
+        let delay = (FLANGER_MIN_DELAY_SECS * SAMPLE_RATE as FreqType).round() as usize;
+        assert_eq!(delay, 24);
+        assert!(output[..delay].iter().all(|sample| sample.abs() < 1e-12));
+        for (output, input) in output[delay..].iter().zip(input.iter()) {
+            assert!((output - input).abs() < 1e-9, "{} {}", output, input);
+        }
+    }
+
+    #[test]
+    fn one_pole_low_pass_passes_dc_and_cuts_highs() {
+        let mut filter = LowPassFilter::new(200.0, SAMPLE_RATE);
+        assert!((settle(&mut filter, 1.0) - 1.0).abs() < 1e-6);
+        // -3dB at the cutoff, then 6dB per octave
+        let mut filter = LowPassFilter::new(200.0, SAMPLE_RATE);
+        let at_cutoff = sine_peak(&mut filter, 200.0);
+        assert!((at_cutoff - FRAC_1_SQRT_2).abs() < 0.02, "{}", at_cutoff);
+        let mut filter = LowPassFilter::new(200.0, SAMPLE_RATE);
+        let above = sine_peak(&mut filter, 3200.0);
+        assert!((0.04..0.08).contains(&above), "{}", above);
+    }
+
+    #[test]
+    fn low_pass_cutoff_sweeps_smoothly() {
+        let mut filter = LowPassFilter::new(200.0, SAMPLE_RATE);
+        let sine = |i: u32| (TAU * 3200.0 * i as FreqType / SAMPLE_RATE as FreqType).sin();
+        for i in 0..SAMPLE_RATE / 10 {
+            filter.process(sine(i));
+        }
+        filter.set_cutoff(20000.0);
+        assert_eq!(filter.cutoff(), 20000.0);
+        // The cutoff is still low a millisecond after the change, and fully open 20ms later
+        let start = SAMPLE_RATE / 10;
+        let peak = |from: u32, to: u32, filter: &mut LowPassFilter| {
+            (from..to)
+                .map(|i| filter.process(sine(i)).abs())
+                .fold(0.0, FreqType::max)
+        };
+        let first_ms = peak(start, start + SAMPLE_RATE / 1000, &mut filter);
+        assert!(first_ms < 0.6, "{}", first_ms);
+        peak(
+            start + SAMPLE_RATE / 1000,
+            start + SAMPLE_RATE / 20,
+            &mut filter,
+        );
+        let open = peak(
+            start + SAMPLE_RATE / 20,
+            start + SAMPLE_RATE / 10,
+            &mut filter,
+        );
+        assert!(open > 0.9, "{}", open);
+    }
+}
@@ -1,31 +1,146 @@
 use crate::{
-    noise_maker::{osc, EnvelopeADSR, FreqType, Note as NoiseMakerNote, WaveType},
-    note::Note,
+    effects::{BiquadFilter, BiquadMode, Effect},
+    lfo::{Lfo, LfoTarget},
+    noise_maker::{
+        osc, pan_gains, w, EnvelopeADSR, FreqType, KarplusStrong, Note as NoiseMakerNote, WaveType,
+    },
+    tuning::{Tuning, TuningType},
 };
+use anyhow::Result;
 use enum_dispatch::enum_dispatch;
+use serde::{Deserialize, Serialize};
+use std::{f64::consts::TAU, fmt, fs, path::Path, sync::Arc};
 
 #[enum_dispatch]
 pub trait Instrument {
-    fn play_note(&self, dt: FreqType, note: &NoiseMakerNote) -> (FreqType, bool) {
-        let amplitude = self.envelope().amplitude(dt, note.on, note.off);
-        let finished = self.max_lifetime() > 0.0 && dt - note.on >= self.max_lifetime();
-        let dt = note.on - dt;
-        let oscillators = self
-            .oscillators()
+    fn name(&self) -> &'static str;
+
+    /// Returns the mid signal of the stereo output and whether the note is finished
+    fn play_note(
+        &self,
+        dt: FreqType,
+        note: &mut NoiseMakerNote,
+        tuning: &TuningType,
+        sample_rate: u32,
+    ) -> (FreqType, bool) {
+        let (left, right, finished) = self.play_note_stereo(dt, note, tuning, sample_rate);
+        ((left + right) / 2.0, finished)
+    }
+
+    /// Returns the left and right samples and whether the note is finished,
+    /// every oscillator is panned before being summed
+    fn play_note_stereo(
+        &self,
+        dt: FreqType,
+        note: &mut NoiseMakerNote,
+        tuning: &TuningType,
+        sample_rate: u32,
+    ) -> (FreqType, FreqType, bool) {
+        let envelope = note.envelope_or(self.envelope());
+        let amplitude = note.envelope_amplitude(&envelope, dt);
+        let finished = (self.max_lifetime() > 0.0 && dt - note.on >= self.max_lifetime())
+            || envelope.is_finished(dt, note.on, note.off);
+        let note_id = note.id;
+        let base_freq = tuning.id_to_freq(note_id);
+        let lfos = self.lfos();
+        let lfo_dt = dt - note.on;
+        let vibrato_semitones = Lfo::sum(lfos, LfoTarget::Pitch, lfo_dt, sample_rate);
+        if vibrato_semitones != 0.0 {
+            // Integrated like the pitch bend so the wave stays continuous
+            let extra_freq = base_freq * (2.0_f64.powf(vibrato_semitones / 12.0) - 1.0);
+            note.lfo_phase += w(extra_freq) / sample_rate as FreqType;
+        }
+        let pan_lfo = Lfo::sum(lfos, LfoTarget::Pan, lfo_dt, sample_rate);
+        let amplitude_lfo = Lfo::sum(lfos, LfoTarget::Amplitude, lfo_dt, sample_rate);
+        // Phase accumulated by gliding from another note, bending and the vibrato,
+        // relative to playing this note all along
+        let glide_phase = note.phase(dt, tuning) + note.bend_phase + note.lfo_phase
+            - w(base_freq) * (dt - note.on);
+        let dt = dt - note.on;
+        let oscillators = self.oscillators();
+        let voices = self.unison_voices().max(1);
+        let unison_detune_cents = self.unison_detune_cents();
+        let rng = &note.rng;
+        let pink_noise = &mut note.pink_noise;
+        let (left, right) = oscillators
             .iter()
-            .map(|config| {
-                config.weight
-                    * osc(
+            .flat_map(|config| {
+                (0..voices).map(move |voice| {
+                    let unison_cents = if voices > 1 {
+                        unison_detune_cents
+                            * (2.0 * voice as FreqType / (voices - 1) as FreqType - 1.0)
+                    } else {
+                        0.0
+                    };
+                    (config, unison_cents)
+                })
+            })
+            .enumerate()
+            .map(|(index, (config, unison_cents))| {
+                let id = (note_id as i16 + config.note_offset as i16).clamp(0, 127) as u8;
+                let freq = tuning.id_to_freq(id)
+                    * 2.0_f64.powf((config.detune_cents + unison_cents) / 1200.0);
+                let glide_offset = glide_phase * freq / base_freq;
+                let modulator = if config.fm_index != 0.0 {
+                    config.fm_index * (w(freq * config.fm_ratio) * dt).sin()
+                } else {
+                    0.0
+                };
+                let mut sound = match config.wave {
+                    // Drawn from the note so a seeded noise maker is reproducible
+                    WaveType::Noise | WaveType::PinkNoise => rng.f64() * 2.0 - 1.0,
+                    _ => osc(
                         dt,
-                        Note::from((note.id as i8 + config.note_offset) as u8).freq(),
-                        config.wave,
-                        config.lfo_hertz,
-                        config.lfo_amplitude,
-                    )
+                        freq,
+                        &config.wave,
+                        glide_offset + modulator + TAU * config.phase_offset,
+                        sample_rate,
+                    ),
+                };
+                if let (WaveType::PinkNoise, Some(pink_noise)) =
+                    (&config.wave, pink_noise.get_mut(index))
+                {
+                    sound = pink_noise.filter(sound);
+                }
+                let sound = config.weight * sound;
+                let (left_gain, right_gain) = pan_gains(config.pan + pan_lfo);
+                (sound * left_gain, sound * right_gain)
             })
-            .sum::<FreqType>();
-        let sound = amplitude * oscillators * self.volume();
-        (sound, finished)
+            .fold((0.0, 0.0), |(acc_left, acc_right), (left, right)| {
+                (acc_left + left, acc_right + right)
+            });
+        let tremolo = 1.0 + self.tremolo_depth() * (w(self.tremolo_rate()) * dt).sin();
+        let weights = if self.normalize_oscillators() {
+            oscillators
+                .iter()
+                .map(|config| config.weight.abs())
+                .sum::<FreqType>()
+                .max(FreqType::EPSILON)
+        } else {
+            1.0
+        };
+        let (left, right) = match self.filter() {
+            Some(config) => {
+                let (left_filter, right_filter) = note.filter.get_or_insert_with(|| {
+                    let filter = BiquadFilter::with_mode(
+                        config.mode,
+                        config.cutoff,
+                        config.resonance,
+                        sample_rate,
+                    );
+                    (filter.clone(), filter)
+                });
+                let octaves = config.envelope_amount * amplitude
+                    + Lfo::sum(lfos, LfoTarget::FilterCutoff, lfo_dt, sample_rate);
+                left_filter.modulate_cutoff(octaves);
+                right_filter.modulate_cutoff(octaves);
+                (left_filter.process(left), right_filter.process(right))
+            }
+            None => (left, right),
+        };
+        let gain =
+            amplitude * tremolo * (1.0 + amplitude_lfo) * self.volume() * note.velocity / weights;
+        (left * gain, right * gain, finished)
     }
 
     fn oscillators(&self) -> Vec<OscillatorConfig> {
@@ -43,24 +158,136 @@ pub trait Instrument {
     fn max_lifetime(&self) -> FreqType {
         1.0
     }
+
+    /// Frequency of the amplitude modulation in Hz
+    fn tremolo_rate(&self) -> FreqType {
+        0.0
+    }
+
+    /// 0.0 disables the tremolo
+    fn tremolo_depth(&self) -> FreqType {
+        0.0
+    }
+
+    /// Number of copies of every oscillator, detuned around the note
+    fn unison_voices(&self) -> usize {
+        1
+    }
+
+    /// Detune of the outermost unison voices, the others are spread evenly in between
+    fn unison_detune_cents(&self) -> FreqType {
+        0.0
+    }
+
+    /// Number of oscillators including the unison voices, each with its own pink noise state
+    fn oscillator_voices(&self) -> usize {
+        self.oscillators().len() * self.unison_voices().max(1)
+    }
+
+    /// Short hits like drums and clicks, they don't count as voices when the notes are mixed
+    fn is_percussive(&self) -> bool {
+        false
+    }
+
+    /// Divides the output by the sum of the oscillator weights, so instruments play at a similar level
+    fn normalize_oscillators(&self) -> bool {
+        false
+    }
+
+    /// LFOs restarted with every note, the filter cutoff LFOs move the cutoff of `filter`
+    fn lfos(&self) -> &[Lfo] {
+        &[]
+    }
+
+    /// Filter applied to every note on its own, so its cutoff can follow the envelope of the note
+    fn filter(&self) -> Option<FilterConfig> {
+        None
+    }
 }
 
 #[enum_dispatch(Instrument)]
+#[derive(Clone)]
 pub enum InstrumentType {
     Default,
     Bell,
     Bell8,
     Harmonica,
     DrumKick,
+    Click,
+    CustomInstrument,
+    Sampler,
+    PluckedString,
+    DrawbarOrgan,
+}
+
+impl InstrumentType {
+    /// Instruments that don't need a file, in the order of the keyboard shortcuts
+    pub fn builtin() -> Vec<InstrumentType> {
+        vec![
+            InstrumentType::from(Default::new()),
+            InstrumentType::from(Bell::new()),
+            InstrumentType::from(Bell8::new()),
+            InstrumentType::from(Harmonica::new()),
+            InstrumentType::from(DrumKick::new()),
+            InstrumentType::from(PluckedString::new()),
+            InstrumentType::from(DrawbarOrgan::new([8, 8, 8, 0, 0, 0, 0, 0, 0])),
+        ]
+    }
+
+    /// Built in instrument with this name, ignoring the case, like `bell` or `drumkick`
+    pub fn from_name(name: &str) -> Option<InstrumentType> {
+        Self::builtin()
+            .into_iter()
+            .find(|instrument| instrument.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for InstrumentType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.name())
+    }
+}
+
+/// Filter of every note of an instrument
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub mode: BiquadMode,
+    /// In Hz, the center frequency of the band-pass
+    pub cutoff: FreqType,
+    /// Q of the filter, resonates around the cutoff above 0.707
+    pub resonance: FreqType,
+    /// Octaves the cutoff moves up at full envelope amplitude, negative moves it down
+    pub envelope_amount: FreqType,
 }
 
-#[derive(Clone, Copy)]
+impl std::default::Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            mode: BiquadMode::Low,
+            cutoff: 1000.0,
+            resonance: 0.707,
+            envelope_amount: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct OscillatorConfig {
     weight: FreqType,
     note_offset: i8,
+    detune_cents: FreqType,
     wave: WaveType,
-    lfo_hertz: FreqType,
-    lfo_amplitude: FreqType,
+    /// Frequency of the sine modulator relative to the oscillator frequency
+    fm_ratio: FreqType,
+    /// Amplitude of the modulator in radians, 0.0 disables FM
+    fm_index: FreqType,
+    /// -1.0 is fully left, 1.0 is fully right
+    pan: FreqType,
+    /// Starting phase in periods, 0.25 starts a quarter period ahead.
+    /// Staggering the oscillators avoids a spike when they would all peak together
+    phase_offset: FreqType,
 }
 
 impl std::default::Default for OscillatorConfig {
@@ -68,31 +295,52 @@ impl std::default::Default for OscillatorConfig {
         Self {
             weight: 1.0,
             note_offset: 0,
+            detune_cents: 0.0,
             wave: WaveType::Sine,
-            lfo_hertz: 0.0,
-            lfo_amplitude: 0.0,
+            fm_ratio: 1.0,
+            fm_index: 0.0,
+            pan: 0.0,
+            phase_offset: 0.0,
         }
     }
 }
 
+impl OscillatorConfig {
+    pub fn wave(&self) -> &WaveType {
+        &self.wave
+    }
+
+    /// Frequency of the oscillator relative to the note, from its note offset and detune in equal
+    /// temperament
+    pub fn freq_ratio(&self) -> FreqType {
+        2.0_f64.powf((self.note_offset as FreqType * 100.0 + self.detune_cents) / 1200.0)
+    }
+}
+
+#[derive(Clone)]
 pub struct Default {}
 
 impl Default {
-    #[allow(dead_code)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {}
     }
 }
 
-impl Instrument for Default {}
+impl Instrument for Default {
+    fn name(&self) -> &'static str {
+        "Default"
+    }
+}
 
+#[derive(Clone)]
 pub struct Bell {
     oscillators: Vec<OscillatorConfig>,
     env: EnvelopeADSR,
 }
 
 impl Bell {
-    #[allow(dead_code)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             env: EnvelopeADSR {
@@ -107,17 +355,18 @@ impl Bell {
                     weight: 1.0,
                     note_offset: 12,
                     wave: WaveType::Sine,
-                    lfo_amplitude: 5.0,
-                    lfo_hertz: 0.001,
+                    ..OscillatorConfig::default()
                 },
                 OscillatorConfig {
                     weight: 0.5,
                     note_offset: 24,
+                    pan: -0.5,
                     ..OscillatorConfig::default()
                 },
                 OscillatorConfig {
                     weight: 0.25,
                     note_offset: 36,
+                    pan: 0.5,
                     ..OscillatorConfig::default()
                 },
             ],
@@ -126,6 +375,10 @@ impl Bell {
 }
 
 impl Instrument for Bell {
+    fn name(&self) -> &'static str {
+        "Bell"
+    }
+
     fn oscillators(&self) -> Vec<OscillatorConfig> {
         self.oscillators.clone()
     }
@@ -133,15 +386,20 @@ impl Instrument for Bell {
     fn envelope(&self) -> EnvelopeADSR {
         self.env
     }
+
+    fn normalize_oscillators(&self) -> bool {
+        true
+    }
 }
 
+#[derive(Clone)]
 pub struct Bell8 {
     oscillators: Vec<OscillatorConfig>,
     env: EnvelopeADSR,
 }
 
 impl Bell8 {
-    #[allow(dead_code)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             env: EnvelopeADSR {
@@ -156,17 +414,18 @@ impl Bell8 {
                     weight: 1.0,
                     note_offset: 12,
                     wave: WaveType::Sine,
-                    lfo_amplitude: 5.0,
-                    lfo_hertz: 0.001,
+                    ..OscillatorConfig::default()
                 },
                 OscillatorConfig {
                     weight: 0.5,
                     note_offset: 24,
+                    pan: -0.5,
                     ..OscillatorConfig::default()
                 },
                 OscillatorConfig {
                     weight: 0.25,
                     note_offset: 36,
+                    pan: 0.5,
                     ..OscillatorConfig::default()
                 },
             ],
@@ -175,6 +434,10 @@ impl Bell8 {
 }
 
 impl Instrument for Bell8 {
+    fn name(&self) -> &'static str {
+        "Bell8"
+    }
+
     fn oscillators(&self) -> Vec<OscillatorConfig> {
         self.oscillators.clone()
     }
@@ -182,15 +445,20 @@ impl Instrument for Bell8 {
     fn envelope(&self) -> EnvelopeADSR {
         self.env
     }
+
+    fn normalize_oscillators(&self) -> bool {
+        true
+    }
 }
 
+#[derive(Clone)]
 pub struct Harmonica {
     oscillators: Vec<OscillatorConfig>,
     env: EnvelopeADSR,
 }
 
 impl Harmonica {
-    #[allow(dead_code)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             env: EnvelopeADSR {
@@ -205,8 +473,7 @@ impl Harmonica {
                     weight: 1.0,
                     note_offset: 0,
                     wave: WaveType::Square,
-                    lfo_amplitude: 5.0,
-                    lfo_hertz: 0.001,
+                    ..OscillatorConfig::default()
                 },
                 OscillatorConfig {
                     weight: 0.5,
@@ -226,6 +493,10 @@ impl Harmonica {
 }
 
 impl Instrument for Harmonica {
+    fn name(&self) -> &'static str {
+        "Harmonica"
+    }
+
     fn oscillators(&self) -> Vec<OscillatorConfig> {
         self.oscillators.clone()
     }
@@ -233,16 +504,22 @@ impl Instrument for Harmonica {
     fn envelope(&self) -> EnvelopeADSR {
         self.env
     }
+
+    fn normalize_oscillators(&self) -> bool {
+        true
+    }
 }
 
+#[derive(Clone)]
 pub struct DrumKick {
     oscillators: Vec<OscillatorConfig>,
     env: EnvelopeADSR,
     max_lifetime: FreqType,
+    lfos: Vec<Lfo>,
 }
 
 impl DrumKick {
-    #[allow(dead_code)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             env: EnvelopeADSR {
@@ -253,13 +530,14 @@ impl DrumKick {
                 ..EnvelopeADSR::default()
             },
             max_lifetime: 1.5,
+            // Raises the pitch slightly while the kick decays
+            lfos: vec![Lfo::new(1.0, WaveType::Sine, 0.5, LfoTarget::Pitch)],
             oscillators: vec![
                 OscillatorConfig {
                     weight: 0.99,
                     note_offset: -36,
                     wave: WaveType::Sine,
-                    lfo_amplitude: 1.0,
-                    lfo_hertz: 1.0,
+                    ..OscillatorConfig::default()
                 },
                 OscillatorConfig {
                     weight: 0.01,
@@ -273,6 +551,10 @@ impl DrumKick {
 }
 
 impl Instrument for DrumKick {
+    fn name(&self) -> &'static str {
+        "DrumKick"
+    }
+
     fn oscillators(&self) -> Vec<OscillatorConfig> {
         self.oscillators.clone()
     }
@@ -284,4 +566,655 @@ impl Instrument for DrumKick {
     fn max_lifetime(&self) -> FreqType {
         self.max_lifetime
     }
+
+    fn is_percussive(&self) -> bool {
+        true
+    }
+
+    fn lfos(&self) -> &[Lfo] {
+        &self.lfos
+    }
+}
+
+/// Short high pitched click, used by the metronome
+#[derive(Clone)]
+pub struct Click {
+    env: EnvelopeADSR,
+}
+
+impl Click {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            env: EnvelopeADSR {
+                attack_time: 0.001,
+                decay_time: 0.03,
+                sustain_amplitude: 0.0,
+                release_time: 0.01,
+                ..EnvelopeADSR::default()
+            },
+        }
+    }
+}
+
+impl Instrument for Click {
+    fn name(&self) -> &'static str {
+        "Click"
+    }
+
+    fn envelope(&self) -> EnvelopeADSR {
+        self.env
+    }
+
+    fn max_lifetime(&self) -> FreqType {
+        0.1
+    }
+
+    fn is_percussive(&self) -> bool {
+        true
+    }
+}
+
+/// Instrument defined at runtime, usually loaded from a toml file
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CustomInstrument {
+    oscillators: Vec<OscillatorConfig>,
+    envelope: EnvelopeADSR,
+    volume: FreqType,
+    max_lifetime: FreqType,
+    tremolo_rate: FreqType,
+    tremolo_depth: FreqType,
+    unison_voices: usize,
+    unison_detune_cents: FreqType,
+    normalize_oscillators: bool,
+    lfos: Vec<Lfo>,
+    filter: Option<FilterConfig>,
+}
+
+impl std::default::Default for CustomInstrument {
+    fn default() -> Self {
+        Self {
+            oscillators: vec![OscillatorConfig::default()],
+            envelope: EnvelopeADSR::default(),
+            volume: 1.0,
+            max_lifetime: 1.0,
+            tremolo_rate: 0.0,
+            tremolo_depth: 0.0,
+            unison_voices: 1,
+            unison_detune_cents: 0.0,
+            normalize_oscillators: false,
+            lfos: Vec::new(),
+            filter: None,
+        }
+    }
+}
+
+impl CustomInstrument {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+}
+
+impl Instrument for CustomInstrument {
+    fn name(&self) -> &'static str {
+        "Custom"
+    }
+
+    fn oscillators(&self) -> Vec<OscillatorConfig> {
+        self.oscillators.clone()
+    }
+
+    fn envelope(&self) -> EnvelopeADSR {
+        self.envelope
+    }
+
+    fn volume(&self) -> FreqType {
+        self.volume
+    }
+
+    fn max_lifetime(&self) -> FreqType {
+        self.max_lifetime
+    }
+
+    fn tremolo_rate(&self) -> FreqType {
+        self.tremolo_rate
+    }
+
+    fn tremolo_depth(&self) -> FreqType {
+        self.tremolo_depth
+    }
+
+    fn unison_voices(&self) -> usize {
+        self.unison_voices
+    }
+
+    fn unison_detune_cents(&self) -> FreqType {
+        self.unison_detune_cents
+    }
+
+    fn normalize_oscillators(&self) -> bool {
+        self.normalize_oscillators
+    }
+
+    fn lfos(&self) -> &[Lfo] {
+        &self.lfos
+    }
+
+    fn filter(&self) -> Option<FilterConfig> {
+        self.filter
+    }
+}
+
+/// Plays a recorded sample, pitched by the ratio between the note and the root note
+#[derive(Clone)]
+pub struct Sampler {
+    /// Mono samples, shared between the copies of the instrument
+    samples: Arc<[FreqType]>,
+    sample_rate: u32,
+    /// Note at which the sample plays at its original speed
+    pub root_note: u8,
+    /// Restarts from the beginning at the end of the sample instead of stopping
+    pub looping: bool,
+    pub envelope: EnvelopeADSR,
+    pub volume: FreqType,
+}
+
+impl Sampler {
+    /// Loads a wav file, multiple channels are mixed down to mono
+    pub fn load(path: impl AsRef<Path>, root_note: u8) -> Result<Self> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let interleaved = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .samples::<f32>()
+                .map(|sample| sample.map(FreqType::from))
+                .collect::<Result<Vec<_>, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 2.0_f64.powi(spec.bits_per_sample as i32 - 1);
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as FreqType / scale))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let samples = interleaved
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<FreqType>() / channels as FreqType)
+            .collect();
+        Ok(Self {
+            samples,
+            sample_rate: spec.sample_rate,
+            root_note,
+            looping: false,
+            envelope: EnvelopeADSR::default(),
+            volume: 1.0,
+        })
+    }
+
+    /// Linearly interpolated sample at a fractional `position`, `None` past the end
+    fn sample_at(&self, position: FreqType) -> Option<FreqType> {
+        let len = self.samples.len();
+        if len == 0 || position < 0.0 {
+            return None;
+        }
+        let position = if self.looping {
+            position % len as FreqType
+        } else {
+            position
+        };
+        let index = position as usize;
+        if index >= len {
+            return None;
+        }
+        let next = if index + 1 < len {
+            self.samples[index + 1]
+        } else if self.looping {
+            self.samples[0]
+        } else {
+            0.0
+        };
+        let frac = position.fract();
+        Some(self.samples[index] * (1.0 - frac) + next * frac)
+    }
+}
+
+impl Instrument for Sampler {
+    fn name(&self) -> &'static str {
+        "Sampler"
+    }
+
+    fn play_note_stereo(
+        &self,
+        dt: FreqType,
+        note: &mut NoiseMakerNote,
+        tuning: &TuningType,
+        _sample_rate: u32,
+    ) -> (FreqType, FreqType, bool) {
+        let envelope = note.envelope_or(self.envelope);
+        let amplitude = note.envelope_amplitude(&envelope, dt);
+        // Periods of the root note elapsed since the note started, follows glides
+        let root_periods =
+            (note.phase(dt, tuning) + note.bend_phase) / w(tuning.id_to_freq(self.root_note));
+        let position = root_periods * self.sample_rate as FreqType;
+        let (sample, finished) = match self.sample_at(position) {
+            Some(sample) => (sample, envelope.is_finished(dt, note.on, note.off)),
+            None => (0.0, true),
+        };
+        let (left_gain, right_gain) = pan_gains(0.0);
+        let sound = sample * amplitude * self.volume * note.velocity;
+        (sound * left_gain, sound * right_gain, finished)
+    }
+
+    fn envelope(&self) -> EnvelopeADSR {
+        self.envelope
+    }
+
+    fn volume(&self) -> FreqType {
+        self.volume
+    }
+
+    fn max_lifetime(&self) -> FreqType {
+        0.0
+    }
+}
+
+/// Guitar or harp like string using Karplus-Strong synthesis
+#[derive(Clone)]
+pub struct PluckedString {
+    /// Gain of every round trip through the string, closer to 1.0 rings longer
+    pub decay: FreqType,
+    pub envelope: EnvelopeADSR,
+    pub volume: FreqType,
+}
+
+impl PluckedString {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            decay: 0.996,
+            envelope: EnvelopeADSR {
+                attack_time: 0.001,
+                decay_time: 0.01,
+                sustain_amplitude: 1.0,
+                release_time: 0.1,
+                ..EnvelopeADSR::default()
+            },
+            volume: 1.0,
+        }
+    }
+}
+
+impl Instrument for PluckedString {
+    fn name(&self) -> &'static str {
+        "PluckedString"
+    }
+
+    /// The length of the string is set when plucked, so it doesn't follow glides or the pitch bend
+    fn play_note_stereo(
+        &self,
+        dt: FreqType,
+        note: &mut NoiseMakerNote,
+        tuning: &TuningType,
+        sample_rate: u32,
+    ) -> (FreqType, FreqType, bool) {
+        let freq = note.freq(dt, tuning);
+        let rng = &note.rng;
+        let string = note
+            .string
+            .get_or_insert_with(|| KarplusStrong::pluck(freq, rng, sample_rate));
+        let sample = string.next(self.decay);
+        let envelope = note.envelope_or(self.envelope);
+        let amplitude = note.envelope_amplitude(&envelope, dt);
+        let finished =
+            (dt - note.on >= self.max_lifetime()) || envelope.is_finished(dt, note.on, note.off);
+        let (left_gain, right_gain) = pan_gains(0.0);
+        let sound = sample * amplitude * self.volume * note.velocity;
+        (sound * left_gain, sound * right_gain, finished)
+    }
+
+    fn envelope(&self) -> EnvelopeADSR {
+        self.envelope
+    }
+
+    fn volume(&self) -> FreqType {
+        self.volume
+    }
+
+    /// Long enough for the string to die out
+    fn max_lifetime(&self) -> FreqType {
+        4.0
+    }
+}
+
+/// Frequency ratios of the drawbars to the note, from the 16' sub-octave to the 1' drawbar
+const DRAWBAR_RATIOS: [FreqType; 9] = [0.5, 1.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0];
+
+/// Tonewheel organ adding nine sines at the harmonics set by the drawbars
+#[derive(Clone)]
+pub struct DrawbarOrgan {
+    oscillators: Vec<OscillatorConfig>,
+    env: EnvelopeADSR,
+}
+
+impl DrawbarOrgan {
+    /// Levels of the drawbars from 0 (pushed in) to 8 (pulled out), like `[8, 8, 8, 0, 0, 0, 0, 0, 0]`
+    pub fn new(drawbars: [u8; 9]) -> Self {
+        let oscillators = DRAWBAR_RATIOS
+            .iter()
+            .zip(drawbars.iter())
+            .map(|(ratio, level)| {
+                // The harmonics aren't all whole semitones, the rest is made up by the detune
+                let cents = 1200.0 * ratio.log2();
+                let note_offset = (cents / 100.0).round();
+                OscillatorConfig {
+                    weight: (*level).min(8) as FreqType / 8.0,
+                    note_offset: note_offset as i8,
+                    detune_cents: cents - note_offset * 100.0,
+                    ..OscillatorConfig::default()
+                }
+            })
+            .collect();
+        Self {
+            oscillators,
+            env: EnvelopeADSR {
+                attack_time: 0.005,
+                decay_time: 0.01,
+                sustain_amplitude: 1.0,
+                release_time: 0.05,
+                ..EnvelopeADSR::default()
+            },
+        }
+    }
+}
+
+impl Instrument for DrawbarOrgan {
+    fn name(&self) -> &'static str {
+        "DrawbarOrgan"
+    }
+
+    fn oscillators(&self) -> Vec<OscillatorConfig> {
+        self.oscillators.clone()
+    }
+
+    fn envelope(&self) -> EnvelopeADSR {
+        self.env
+    }
+
+    /// Plays as long as the key is held
+    fn max_lifetime(&self) -> FreqType {
+        0.0
+    }
+
+    /// Pulling more drawbars changes the tone without getting louder
+    fn normalize_oscillators(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise_maker::{render, NoteEvent};
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// Peak of the left channel of interleaved stereo `samples` between `from` and `to` seconds
+    fn peak(samples: &[f32], from: FreqType, to: FreqType) -> FreqType {
+        let frame = |secs: FreqType| (secs * SAMPLE_RATE as FreqType) as usize;
+        samples[frame(from) * 2..frame(to) * 2]
+            .iter()
+            .step_by(2)
+            .fold(0.0, |peak, sample| peak.max(sample.abs() as FreqType))
+    }
+
+    /// Renders A4 at full velocity played by `instrument`
+    fn play(instrument: InstrumentType, duration_secs: FreqType) -> Vec<f32> {
+        play_with_velocity(instrument, 1.0, duration_secs)
+    }
+
+    fn play_with_velocity(
+        instrument: InstrumentType,
+        velocity: FreqType,
+        duration_secs: FreqType,
+    ) -> Vec<f32> {
+        let events = [(
+            0.0,
+            NoteEvent::On {
+                id: 69,
+                instrument_id: 0,
+                velocity,
+            },
+        )];
+        render(
+            vec![instrument],
+            &events,
+            duration_secs,
+            Some(1),
+            SAMPLE_RATE,
+        )
+    }
+
+    #[test]
+    fn amplitude_lfo_gives_the_tremolo_depth() {
+        let instrument: CustomInstrument = toml::from_str(
+            r#"
+            [envelope]
+            attack_time = 0.001
+            decay_time = 0.001
+            sustain_amplitude = 1.0
+
+            [[lfos]]
+            rate = 5.0
+            wave = "Sine"
+            depth = 0.5
+            target = "Amplitude"
+            "#,
+        )
+        .unwrap();
+        let samples = play(InstrumentType::from(instrument), 0.4);
+        // The LFO is at its highest 50ms after the note starts and at its lowest 100ms later
+        let high = peak(&samples, 0.045, 0.055);
+        let low = peak(&samples, 0.145, 0.155);
+        let ratio = high / low;
+        assert!((ratio - 3.0).abs() < 0.1, "{}", ratio);
+    }
+
+    #[test]
+    fn normalized_bell_plays_at_the_level_of_default() {
+        let bell = Bell::new();
+        let unnormalized = CustomInstrument {
+            oscillators: bell.oscillators.clone(),
+            envelope: bell.env,
+            ..<CustomInstrument as std::default::Default>::default()
+        };
+        // Both at the top of the attack of the bell
+        let bell_peak = peak(&play(InstrumentType::from(bell), 0.1), 0.01, 0.03);
+        let unnormalized_peak = peak(&play(InstrumentType::from(unnormalized), 0.1), 0.01, 0.03);
+        let ratio = unnormalized_peak / bell_peak;
+        assert!((ratio - 1.75).abs() < 0.01, "{}", ratio);
+
+        // At the end of the attack and decay of default
+        let default_peak = peak(&play(InstrumentType::from(Default::new()), 0.3), 0.2, 0.3);
+        let ratio = bell_peak / default_peak;
+        assert!((0.6..=1.1).contains(&ratio), "{}", ratio);
+    }
+
+    #[test]
+    fn half_velocity_plays_at_half_the_amplitude() {
+        let instrument = InstrumentType::from(Default::new());
+        let full = play_with_velocity(instrument.clone(), 1.0, 0.3);
+        let half = play_with_velocity(instrument, 0.5, 0.3);
+        assert!(full.iter().any(|sample| sample.abs() > 0.1));
+        for (full, half) in full.iter().zip(half.iter()) {
+            assert!((full * 0.5 - half).abs() < 1e-6, "{} {}", full, half);
+        }
+    }
+
+    #[test]
+    fn saw_rises_after_the_onset() {
+        let instrument: CustomInstrument = toml::from_str(
+            r#"
+            oscillators = [{ wave = "SawFast" }]
+
+            [envelope]
+            attack_time = 0.000001
+            sustain_amplitude = 1.0
+            start_amplitude = 1.0
+            "#,
+        )
+        .unwrap();
+        let samples = play(InstrumentType::from(instrument), 0.01);
+        let left = samples.iter().step_by(2).copied().collect::<Vec<_>>();
+        assert!(left[2] < 0.0);
+        assert!(left[2..20].windows(2).all(|pair| pair[1] > pair[0]));
+    }
+
+    /// Magnitude of the `hertz` component of the left channel between `from` and `to` seconds,
+    /// relative to a full scale sine
+    fn magnitude(samples: &[f32], hertz: FreqType, from: FreqType, to: FreqType) -> FreqType {
+        let frame = |secs: FreqType| (secs * SAMPLE_RATE as FreqType) as usize;
+        let (from, to) = (frame(from), frame(to));
+        let (re, im) = (from..to).fold((0.0, 0.0), |(re, im), i| {
+            let sample = samples[i * 2] as FreqType;
+            let phase = TAU * hertz * i as FreqType / SAMPLE_RATE as FreqType;
+            (re + sample * phase.cos(), im + sample * phase.sin())
+        });
+        2.0 * (re * re + im * im).sqrt() / (to - from) as FreqType
+    }
+
+    #[test]
+    fn fm_adds_sidebands_around_the_carrier() {
+        let fm = |index: FreqType| {
+            let instrument = CustomInstrument {
+                oscillators: vec![OscillatorConfig {
+                    fm_ratio: 0.5,
+                    fm_index: index,
+                    ..OscillatorConfig::default()
+                }],
+                envelope: EnvelopeADSR {
+                    sustain_amplitude: 1.0,
+                    ..EnvelopeADSR::default()
+                },
+                ..<CustomInstrument as std::default::Default>::default()
+            };
+            play(InstrumentType::from(instrument), 0.5)
+        };
+        // A4 modulated at 220Hz has sidebands at 220Hz and 660Hz, with an index of 1.0
+        // the first sidebands are at J1(1) / J0(1) of the carrier
+        let sine = fm(0.0);
+        let modulated = fm(1.0);
+        assert!(magnitude(&sine, 660.0, 0.3, 0.5) < 1e-6);
+        let ratio = magnitude(&modulated, 660.0, 0.3, 0.5) / magnitude(&modulated, 440.0, 0.3, 0.5);
+        assert!((ratio - 0.575).abs() < 0.02, "{}", ratio);
+    }
+
+    #[test]
+    fn pink_noise_falls_off_with_frequency_and_white_noise_does_not() {
+        let noise = |wave: WaveType| {
+            let instrument = CustomInstrument {
+                oscillators: vec![OscillatorConfig {
+                    wave,
+                    ..OscillatorConfig::default()
+                }],
+                envelope: EnvelopeADSR {
+                    sustain_amplitude: 1.0,
+                    ..EnvelopeADSR::default()
+                },
+                ..<CustomInstrument as std::default::Default>::default()
+            };
+            let samples = play(InstrumentType::from(instrument), 0.9);
+            let power = |from: u32, to: u32, step: usize| {
+                let bins = (from..to).step_by(step).collect::<Vec<_>>();
+                bins.iter()
+                    .map(|hertz| magnitude(&samples, *hertz as FreqType, 0.1, 0.9).powi(2))
+                    .sum::<FreqType>()
+                    / bins.len() as FreqType
+            };
+            // The low band is 5 octaves below the high one
+            power(100, 300, 5) / power(3200, 9600, 160)
+        };
+        let white = noise(WaveType::Noise);
+        let pink = noise(WaveType::PinkNoise);
+        assert!((0.3..3.0).contains(&white), "{}", white);
+        // -3dB per octave is 32 times less power 5 octaves higher
+        assert!((10.0..100.0).contains(&pink), "{}", pink);
+    }
+
+    #[test]
+    fn unison_voices_add_up_before_normalization() {
+        let unison = |voices: usize| CustomInstrument {
+            unison_voices: voices,
+            ..<CustomInstrument as std::default::Default>::default()
+        };
+        // Without detune the voices are in phase
+        let single = play(InstrumentType::from(unison(1)), 0.3);
+        let triple = play(InstrumentType::from(unison(3)), 0.3);
+        assert!(single.iter().any(|sample| sample.abs() > 0.1));
+        for (single, triple) in single.iter().zip(triple.iter()) {
+            assert!(
+                (single * 3.0 - triple).abs() < 1e-5,
+                "{} {}",
+                single,
+                triple
+            );
+        }
+    }
+
+    #[test]
+    fn quarter_turn_phase_offset_plays_a_cosine() {
+        let offset = |phase_offset: FreqType| {
+            let instrument = CustomInstrument {
+                oscillators: vec![OscillatorConfig {
+                    phase_offset,
+                    ..OscillatorConfig::default()
+                }],
+                ..<CustomInstrument as std::default::Default>::default()
+            };
+            let samples = play(InstrumentType::from(instrument), 0.5);
+            // After the attack, where the envelope is flat
+            samples[(0.3 * SAMPLE_RATE as FreqType) as usize * 2..]
+                .iter()
+                .step_by(2)
+                .map(|sample| *sample as FreqType)
+                .collect::<Vec<_>>()
+        };
+        let sine = offset(0.0);
+        let cosine = offset(0.25);
+        let peak = sine
+            .iter()
+            .fold(0.0, |peak: FreqType, sample| peak.max(sample.abs()));
+        for (sine, cosine) in sine.iter().zip(cosine.iter()) {
+            let amplitude = (sine * sine + cosine * cosine).sqrt();
+            assert!(
+                (amplitude - peak).abs() < 0.01 * peak,
+                "{} {}",
+                amplitude,
+                peak
+            );
+        }
+        // Ahead of the sine, not behind it, so it follows the slope of the sine
+        let slope = (1..sine.len() - 1)
+            .map(|i| cosine[i] * (sine[i + 1] - sine[i - 1]))
+            .sum::<FreqType>();
+        assert!(slope > 0.0);
+    }
+
+    #[test]
+    fn oscillator_freq_ratio_follows_the_note_offset_and_detune() {
+        let octave_up = OscillatorConfig {
+            note_offset: 12,
+            ..OscillatorConfig::default()
+        };
+        assert_eq!(octave_up.freq_ratio(), 2.0);
+        let fifth_minus_two_cents = OscillatorConfig {
+            note_offset: 7,
+            detune_cents: -2.0,
+            ..OscillatorConfig::default()
+        };
+        let expected = 2.0_f64.powf(698.0 / 1200.0);
+        assert!((fifth_minus_two_cents.freq_ratio() - expected).abs() < 1e-12);
+    }
 }
@@ -0,0 +1,241 @@
+use crate::note::Note;
+use device_query::Keycode;
+use std::fmt::Write;
+
+/// Layout of the computer keyboard, the notes are played by the keys at the same places on all of them
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum KeyboardLayout {
+    Qwerty,
+    Azerty,
+    Dvorak,
+}
+
+static QWERTY_KEYS: [(Keycode, u8); 17] = [
+    (Keycode::Z, 0),
+    (Keycode::S, 1),
+    (Keycode::X, 2),
+    (Keycode::D, 3),
+    (Keycode::C, 4),
+    (Keycode::V, 5),
+    (Keycode::G, 6),
+    (Keycode::B, 7),
+    (Keycode::H, 8),
+    (Keycode::N, 9),
+    (Keycode::J, 10),
+    (Keycode::M, 11),
+    (Keycode::Comma, 12),
+    (Keycode::L, 13),
+    (Keycode::Dot, 14),
+    (Keycode::Semicolon, 15),
+    (Keycode::Slash, 16),
+];
+
+/// There is no `Keycode` for the colon and exclamation mark keys, so D5 and E5 can't be played
+static AZERTY_KEYS: [(Keycode, u8); 15] = [
+    (Keycode::W, 0),
+    (Keycode::S, 1),
+    (Keycode::X, 2),
+    (Keycode::D, 3),
+    (Keycode::C, 4),
+    (Keycode::V, 5),
+    (Keycode::G, 6),
+    (Keycode::B, 7),
+    (Keycode::H, 8),
+    (Keycode::N, 9),
+    (Keycode::J, 10),
+    (Keycode::Comma, 11),
+    (Keycode::Semicolon, 12),
+    (Keycode::L, 13),
+    (Keycode::M, 15),
+];
+
+static DVORAK_KEYS: [(Keycode, u8); 17] = [
+    (Keycode::Semicolon, 0),
+    (Keycode::O, 1),
+    (Keycode::Q, 2),
+    (Keycode::E, 3),
+    (Keycode::J, 4),
+    (Keycode::K, 5),
+    (Keycode::I, 6),
+    (Keycode::X, 7),
+    (Keycode::D, 8),
+    (Keycode::B, 9),
+    (Keycode::H, 10),
+    (Keycode::M, 11),
+    (Keycode::W, 12),
+    (Keycode::N, 13),
+    (Keycode::V, 14),
+    (Keycode::S, 15),
+    (Keycode::Z, 16),
+];
+
+/// Number of notes playable at once from the keyboard, starting at the C of the current octave
+pub const NUM_KEYBOARD_NOTES: usize = 17;
+
+impl KeyboardLayout {
+    /// Keys with the offset of their note in semitones from the C of the current octave.
+    /// `Keycode` isn't hashable so the mapping is a slice instead of a map.
+    pub fn keys(self) -> &'static [(Keycode, u8)] {
+        match self {
+            KeyboardLayout::Qwerty => &QWERTY_KEYS,
+            KeyboardLayout::Azerty => &AZERTY_KEYS,
+            KeyboardLayout::Dvorak => &DVORAK_KEYS,
+        }
+    }
+
+    /// Offset of the note played by `key`, if it plays one
+    pub fn note_offset(self, key: &Keycode) -> Option<u8> {
+        self.keys()
+            .iter()
+            .find(|(layout_key, _)| layout_key == key)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+/// Character on `key`
+fn key_label(key: &Keycode) -> String {
+    match key {
+        Keycode::Comma => ",".to_string(),
+        Keycode::Dot => ".".to_string(),
+        Keycode::Semicolon => ";".to_string(),
+        Keycode::Slash => "/".to_string(),
+        key => format!("{:?}", key),
+    }
+}
+
+/// Keys of a layout playing the notes from `base`, which moves by octaves while playing
+#[derive(Clone, Copy, Debug)]
+pub struct KeyboardMapping {
+    pub layout: KeyboardLayout,
+    /// Midi note played by the first key
+    base: u8,
+    /// Only the first keys of the layout play notes
+    num_keys: usize,
+}
+
+impl KeyboardMapping {
+    /// `num_keys` is at most `NUM_KEYBOARD_NOTES`, `base` is lowered if the last key would be
+    /// above the midi range
+    pub fn new(layout: KeyboardLayout, base: u8, num_keys: usize) -> Self {
+        let num_keys = num_keys.clamp(1, NUM_KEYBOARD_NOTES);
+        Self {
+            layout,
+            base: base.min(127 - (num_keys - 1) as u8),
+            num_keys,
+        }
+    }
+
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+
+    /// Keys playing a note, with the offset of their note from `base`
+    pub fn keys(&self) -> impl Iterator<Item = &'static (Keycode, u8)> {
+        let num_keys = self.num_keys;
+        self.layout
+            .keys()
+            .iter()
+            .filter(move |(_, offset)| (*offset as usize) < num_keys)
+    }
+
+    /// Moves the base by `octaves`, unless a key would leave the midi range
+    pub fn shift_octaves(&mut self, octaves: i16) {
+        let base = self.base as i16 + 12 * octaves;
+        let highest_base = 127 - (self.num_keys as i16 - 1);
+        if (0..=highest_base).contains(&base) {
+            self.base = base as u8;
+        }
+    }
+
+    /// The keys over the notes they play, aligned in columns
+    pub fn diagram(&self) -> String {
+        let mut keys = "Key ".to_string();
+        let mut notes = "Note".to_string();
+        for (key, offset) in self.keys() {
+            // Writing to a String can't fail
+            let _ = write!(keys, " {:<4}", key_label(key));
+            let _ = write!(notes, " {:<4}", Note::from(self.base + offset).to_string());
+        }
+        format!("{}\n{}", keys.trim_end(), notes.trim_end())
+    }
+}
+
+/// Only reports a key as pressed or released once it stayed that way for a few polls,
+/// so a key bouncing between polls doesn't play the note several times
+pub struct KeyDebouncer {
+    polls: u32,
+    stable: Vec<Keycode>,
+    /// Keys whose state differs from the stable state, with the number of polls it did
+    pending: Vec<(Keycode, u32)>,
+}
+
+impl KeyDebouncer {
+    pub fn new(polls: u32) -> Self {
+        Self {
+            polls: polls.max(1),
+            stable: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Takes the keys currently down and returns the debounced keys
+    pub fn update(&mut self, keys: &[Keycode]) -> Vec<Keycode> {
+        let changed = keys
+            .iter()
+            .filter(|key| !self.stable.contains(key))
+            .chain(self.stable.iter().filter(|key| !keys.contains(key)))
+            .cloned()
+            .collect::<Vec<_>>();
+        // Keys back to their stable state start over
+        self.pending.retain(|(key, _)| changed.contains(key));
+        for key in changed {
+            let polls = match self.pending.iter_mut().find(|(pending, _)| *pending == key) {
+                Some((_, polls)) => {
+                    *polls += 1;
+                    *polls
+                }
+                None => {
+                    self.pending.push((key.clone(), 1));
+                    1
+                }
+            };
+            if polls >= self.polls {
+                self.pending.retain(|(pending, _)| *pending != key);
+                if keys.contains(&key) {
+                    self.stable.push(key);
+                } else {
+                    self.stable.retain(|stable| *stable != key);
+                }
+            }
+        }
+        self.stable.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_play_their_own_keys() {
+        assert_eq!(KeyboardLayout::Azerty.note_offset(&Keycode::W), Some(0));
+        assert_eq!(KeyboardLayout::Azerty.note_offset(&Keycode::Z), None);
+        assert_eq!(KeyboardLayout::Qwerty.note_offset(&Keycode::Z), Some(0));
+        assert_eq!(
+            KeyboardLayout::Dvorak.note_offset(&Keycode::Semicolon),
+            Some(0)
+        );
+        assert_eq!(KeyboardLayout::Dvorak.note_offset(&Keycode::M), Some(11));
+
+        let qwerty = KeyboardMapping::new(KeyboardLayout::Qwerty, 60, 5);
+        let azerty = KeyboardMapping::new(KeyboardLayout::Azerty, 60, 5);
+        assert_eq!(
+            qwerty.diagram(),
+            "Key  Z    S    X    D    C\nNote C4   C#4  D4   D#4  E4"
+        );
+        assert_eq!(
+            azerty.diagram(),
+            "Key  W    S    X    D    C\nNote C4   C#4  D4   D#4  E4"
+        );
+    }
+}
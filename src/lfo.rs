@@ -0,0 +1,77 @@
+use crate::noise_maker::{osc, FreqType, WaveType};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// What an `Lfo` modulates, the unit of its depth depends on it
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum LfoTarget {
+    /// Vibrato, depth in semitones
+    Pitch,
+    /// Tremolo, depth as a fraction of the amplitude
+    Amplitude,
+    /// Depth in octaves, moves the cutoff of the filters
+    FilterCutoff,
+    /// Depth added to the pan, -1.0 is fully left and 1.0 is fully right
+    Pan,
+}
+
+/// Low frequency oscillator modulating a target
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Lfo {
+    /// Frequency in Hz
+    pub rate: FreqType,
+    pub wave: WaveType,
+    /// 0.0 disables the LFO
+    pub depth: FreqType,
+    pub target: LfoTarget,
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Self {
+            rate: 5.0,
+            wave: WaveType::Sine,
+            depth: 0.0,
+            target: LfoTarget::Pitch,
+        }
+    }
+}
+
+impl Lfo {
+    pub fn new(rate: FreqType, wave: WaveType, depth: FreqType, target: LfoTarget) -> Self {
+        Self {
+            rate,
+            wave,
+            depth,
+            target,
+        }
+    }
+
+    /// Modulation `dt` seconds after the LFO started, between -depth and depth
+    pub fn value(&self, dt: FreqType, sample_rate: u32) -> FreqType {
+        if self.depth == 0.0 {
+            return 0.0;
+        }
+        self.depth * osc(dt, self.rate, &self.wave, 0.0, sample_rate)
+    }
+
+    /// Loads the `[[lfos]]` tables of a toml file, written like the LFOs of a custom instrument
+    pub fn load_list(path: impl AsRef<Path>) -> Result<Vec<Lfo>> {
+        #[derive(Deserialize)]
+        struct LfoList {
+            lfos: Vec<Lfo>,
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str::<LfoList>(&content)?.lfos)
+    }
+
+    /// Sum of the LFOs routed to `target`, they add up when several modulate the same target
+    pub fn sum(lfos: &[Lfo], target: LfoTarget, dt: FreqType, sample_rate: u32) -> FreqType {
+        lfos.iter()
+            .filter(|lfo| lfo.target == target)
+            .map(|lfo| lfo.value(dt, sample_rate))
+            .sum()
+    }
+}
@@ -0,0 +1,17 @@
+pub mod effects;
+pub mod instruments;
+pub mod keyboard;
+pub mod lfo;
+pub mod midi;
+pub mod midi_file;
+pub mod noise_maker;
+pub mod note;
+pub mod plot;
+#[cfg(feature = "audio")]
+pub mod recorder;
+pub mod sequencer;
+pub mod smoothed_param;
+pub mod status;
+pub mod tuning;
+
+pub use noise_maker::render;
@@ -1,111 +1,1143 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use cpal::traits::{DeviceTrait, HostTrait};
 use device_query::{DeviceQuery, DeviceState, Keycode};
-use instruments::{Default, InstrumentType};
-use noise_maker::{NoiseMaker, NoiseMakerData, Note as NoiseMakerNote};
-use note::Note;
-use rodio::{OutputStream, Sink};
-use std::sync::{Arc, Mutex};
+use rodio::{OutputStream, Sink, Source};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use synth_rs::{
+    effects::{EffectType, LowPassFilter},
+    instruments::{Click, CustomInstrument, DrumKick, Instrument, InstrumentType, Sampler},
+    keyboard::{KeyDebouncer, KeyboardLayout, KeyboardMapping, NUM_KEYBOARD_NOTES},
+    lfo::Lfo,
+    midi,
+    midi_file::{self, MidiFilePlayer},
+    noise_maker::{
+        lock_status, EnvelopeADSR, FreqType, NoiseMaker, NoiseMakerData, NoiseMakerStatus,
+        NoteEvent, DEFAULT_SAMPLE_RATE,
+    },
+    note::{chord_from_root, quantize_to_scale, ChordType, Note, Scale},
+    plot,
+    recorder::{Recorder, RecorderHandle},
+    sequencer::{glissando, ArpPattern, Arpeggiator, Metronome, NoteDivision, Step, StepSequencer},
+    status,
+    tuning::{EqualDivisionsOfOctave, JustIntonation, TuningType},
+};
 
-mod instruments;
-mod noise_maker;
-mod note;
+pub const VOLUME_STEP: FreqType = 0.05;
+pub const SUSTAIN_KEY: Keycode = Keycode::Space;
+pub const ARPEGGIATOR_KEY: Keycode = Keycode::Tab;
+pub const SEQUENCER_KEY: Keycode = Keycode::Enter;
+pub const METRONOME_KEY: Keycode = Keycode::Grave;
+pub const MIDI_FILE_KEY: Keycode = Keycode::Backspace;
+pub const MONO_KEY: Keycode = Keycode::P;
+pub const LEGATO_KEY: Keycode = Keycode::R;
+pub const CHORD_KEY: Keycode = Keycode::T;
+/// Runs up two octaves of the scale from the note of the first key
+pub const GLISSANDO_KEY: Keycode = Keycode::Y;
+pub const GLISSANDO_SECS: FreqType = 2.0;
+pub const WAVEFORM_KEY: Keycode = Keycode::F11;
+pub const FREEZE_KEY: Keycode = Keycode::F12;
+pub const BEND_UP_KEY: Keycode = Keycode::Up;
+pub const BEND_DOWN_KEY: Keycode = Keycode::Down;
+/// Pitch bend while a bend key is held, like a pitch wheel pushed to the end
+pub const KEY_BEND_SEMITONES: FreqType = 2.0;
+/// Decrease and increase keys of the depth of the global vibrato
+pub const VIBRATO_DEPTH_KEYS: (Keycode, Keycode) = (Keycode::NumpadSubtract, Keycode::NumpadAdd);
+/// Decrease and increase keys of the rate of the global vibrato
+pub const VIBRATO_RATE_KEYS: (Keycode, Keycode) = (Keycode::NumpadDivide, Keycode::NumpadMultiply);
+pub const VIBRATO_DEPTH_STEP: FreqType = 0.05;
+pub const MAX_VIBRATO_DEPTH: FreqType = 2.0;
+/// The rate is multiplied or divided by this
+pub const VIBRATO_RATE_FACTOR: FreqType = 1.25;
+pub const MIN_VIBRATO_RATE: FreqType = 0.5;
+pub const MAX_VIBRATO_RATE: FreqType = 20.0;
+/// Decrease and increase keys of the cutoff of the `--low-pass` filter
+pub const CUTOFF_KEYS: (Keycode, Keycode) = (Keycode::Numpad2, Keycode::Numpad8);
+/// The cutoff is multiplied or divided by this, about a third of an octave
+pub const CUTOFF_FACTOR: FreqType = 1.25;
+pub const MIN_CUTOFF: FreqType = 20.0;
+pub const MAX_CUTOFF: FreqType = 20000.0;
+/// The output device is considered dead when no sample was requested for this long
+pub const OUTPUT_TIMEOUT: Duration = Duration::from_secs(1);
+pub const MAX_OUTPUT_REOPEN_ATTEMPTS: u32 = 3;
+/// The keys are polled at 120Hz
+pub const POLL_INTERVAL: Duration = Duration::from_micros(1_000_000 / 120);
+/// Number of polls a key has to stay pressed or released before it counts
+pub const DEBOUNCE_POLLS: u32 = 2;
+pub const INSTRUMENT_KEYS: [Keycode; 9] = [
+    Keycode::Key1,
+    Keycode::Key2,
+    Keycode::Key3,
+    Keycode::Key4,
+    Keycode::Key5,
+    Keycode::Key6,
+    Keycode::Key7,
+    Keycode::Key8,
+    Keycode::Key9,
+];
+/// Decrease and increase keys of the attack, decay, sustain and release of the selected instrument
+pub const ENVELOPE_KEYS: [(Keycode, Keycode); 4] = [
+    (Keycode::Delete, Keycode::Insert),
+    (Keycode::End, Keycode::Home),
+    (Keycode::PageDown, Keycode::PageUp),
+    (Keycode::Left, Keycode::Right),
+];
+/// The envelope times are multiplied or divided by this, so short times can be tweaked finely
+pub const ENVELOPE_TIME_FACTOR: FreqType = 1.25;
+pub const MIN_ENVELOPE_TIME: FreqType = 0.001;
+pub const SUSTAIN_STEP: FreqType = 0.05;
+/// Set the velocity of new notes from 0.1 to 1.0
+pub const VELOCITY_KEYS: [Keycode; 10] = [
+    Keycode::F1,
+    Keycode::F2,
+    Keycode::F3,
+    Keycode::F4,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F7,
+    Keycode::F8,
+    Keycode::F9,
+    Keycode::F10,
+];
 
-pub const KEYBOARD_OFFSET: i32 = 9; // Note is computed from A, but keyboard starts at C
+#[derive(Parser)]
+struct Args {
+    /// Record the output to this wav file, written when exiting with Escape
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Play with a built in instrument by name, like `bell`, or one loaded from this toml file
+    #[arg(long)]
+    instrument: Option<PathBuf>,
+    /// Play a wav file pitched by the notes
+    #[arg(long)]
+    sample: Option<PathBuf>,
+    /// Note at which the sample plays at its original pitch
+    #[arg(long, default_value = "C4")]
+    sample_root: Note,
+    /// Play notes from the midi input port at this index
+    #[arg(long)]
+    midi: Option<usize>,
+    /// Play this midi file, its channels are played by the instruments in order
+    #[arg(long)]
+    midi_file: Option<PathBuf>,
+    /// List the available midi input ports
+    #[arg(long)]
+    list_midi: bool,
+    /// Layout of the computer keyboard, the keys playing notes keep their places on every layout
+    #[arg(long, value_enum, default_value_t = KeyboardLayout::Qwerty)]
+    layout: KeyboardLayout,
+    /// Note played by the first key of the keyboard, moved by octaves with the bracket keys
+    #[arg(long, default_value = "C4")]
+    base_note: Note,
+    /// Number of keys playing notes, from the first key of the layout
+    #[arg(long, default_value_t = NUM_KEYBOARD_NOTES)]
+    keys: usize,
+    /// List the available audio output devices
+    #[arg(long)]
+    list_devices: bool,
+    /// Play on the output device with this index or name instead of the default one
+    #[arg(long)]
+    device: Option<String>,
+    /// Output sample rate in Hz
+    #[arg(long, default_value_t = DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    /// Use just intonation relative to this tonic, like `C4`, instead of equal temperament
+    #[arg(long)]
+    just_intonation: Option<Note>,
+    /// Divide the octave in this many equal steps instead of 12, like 24 for quarter tones.
+    /// Every key plays the next step and A4 stays at 440Hz
+    #[arg(long, conflicts_with = "just_intonation")]
+    edo: Option<u16>,
+    /// Snap the notes played on the keyboard to this scale
+    #[arg(long, value_enum)]
+    scale: Option<Scale>,
+    /// Tonic of `--scale`, only the letter and accidental matter
+    #[arg(long, default_value = "C4")]
+    scale_tonic: Note,
+    /// Render `--notes` to this wav file without opening an audio device or reading the keyboard
+    #[arg(long)]
+    render: Option<PathBuf>,
+    /// Length of the `--render` in seconds
+    #[arg(long, default_value_t = 2.0)]
+    duration: FreqType,
+    /// Notes played one after the other by `--render`, like `C4,E4,G4`
+    #[arg(long, value_delimiter = ',', default_value = "C4")]
+    notes: Vec<Note>,
+    /// Seed of the noise, the same seed renders the same output
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Pans the notes by pitch like a piano, low notes left and high notes right, 0.0 keeps them
+    /// centered
+    #[arg(long, default_value_t = 0.0)]
+    keyboard_pan_spread: FreqType,
+    /// Modulate the whole output with the `[[lfos]]` of this toml file, written like the LFOs of a
+    /// custom instrument. They don't restart with the notes and can't modulate the pitch
+    #[arg(long)]
+    master_lfos: Option<PathBuf>,
+    /// Filter the whole output with a low-pass at this cutoff in Hz, swept with the cutoff keys
+    #[arg(long)]
+    low_pass: Option<FreqType>,
+    /// Write the output to stdout as raw 32 bit float little endian interleaved stereo instead of
+    /// playing it, the status is printed to stderr
+    #[arg(long)]
+    stdout: bool,
+}
+
+/// Set by the SIGINT handler, the main loop exits cleanly like with Escape
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_interrupt() {
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    // Only stores to an atomic, which is safe in a signal handler
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_interrupt() {}
 
 fn main() -> Result<()> {
-    let instruments = vec![InstrumentType::from(Default::new())];
-    let data = Arc::new(Mutex::new(NoiseMakerData::default()));
+    let args = Args::parse();
+    if args.list_midi {
+        for (index, name) in midi::port_names()?.iter().enumerate() {
+            println!("{}: {}", index, name);
+        }
+        return Ok(());
+    }
+    if args.list_devices {
+        for (index, device) in output_devices()?.iter().enumerate() {
+            println!("{}: {}", index, device.name()?);
+        }
+        return Ok(());
+    }
 
-    let (_stream, stream_handle) = OutputStream::try_default()?;
-    let sink = Sink::try_new(&stream_handle)?;
-    sink.set_volume(0.2);
-    sink.append(NoiseMaker::new(data.clone(), instruments));
+    let mut instruments = InstrumentType::builtin();
+    let drum_kick_id = instruments
+        .iter()
+        .position(|instrument| instrument.name() == DrumKick::new().name())
+        .unwrap_or_default();
+    // Start with the selected or last loaded instrument
+    let mut instrument_id = 0;
+    if let Some(path) = &args.instrument {
+        let builtin_id = path
+            .to_str()
+            .and_then(InstrumentType::from_name)
+            .and_then(|builtin| {
+                instruments
+                    .iter()
+                    .position(|instrument| instrument.name() == builtin.name())
+            });
+        instrument_id = match builtin_id {
+            Some(id) => id,
+            None => {
+                let custom = CustomInstrument::load(path).map_err(|err| {
+                    anyhow!(
+                        "{} is neither a built in instrument nor an instrument file: {}",
+                        path.display(),
+                        err
+                    )
+                })?;
+                instruments.push(InstrumentType::from(custom));
+                instruments.len() - 1
+            }
+        };
+    }
+    if let Some(path) = &args.sample {
+        instruments.push(InstrumentType::from(Sampler::load(
+            path,
+            args.sample_root.into_u8(),
+        )?));
+        instrument_id = instruments.len() - 1;
+    }
+    if let Some(path) = &args.render {
+        return render_notes(
+            path,
+            instruments,
+            instrument_id,
+            &args.notes,
+            args.duration,
+            args.seed,
+            args.sample_rate,
+        );
+    }
+    let master_lfos = match &args.master_lfos {
+        Some(path) => Lfo::load_list(path)?,
+        None => Vec::new(),
+    };
+    let just_intonation_tonic = args.just_intonation.map(|tonic| tonic.into_u8());
+    // Starts at `dt` so the players keep their timing when the output is reopened
+    let new_data = |dt: FreqType| {
+        let mut data = NoiseMakerData::default();
+        data.lfos = master_lfos.clone();
+        if let Some(seed) = args.seed {
+            data.seed(seed);
+        }
+        data.keyboard_pan_spread = args.keyboard_pan_spread;
+        if let Some(cutoff) = args.low_pass {
+            data.effects.push(EffectType::from(LowPassFilter::new(
+                cutoff,
+                args.sample_rate,
+            )));
+        }
+        if let Some(tonic) = just_intonation_tonic {
+            data.tuning = TuningType::from(JustIntonation::new(tonic));
+        }
+        if let Some(divisions) = args.edo {
+            data.tuning = TuningType::from(EqualDivisionsOfOctave(divisions));
+        }
+        data.dt = dt;
+        data
+    };
+    let mut settings = Settings::new(&new_data(0.0));
+    let audio_status = Arc::new(Mutex::new(NoiseMakerStatus::default()));
+    let (events, events_receiver) = crossbeam_channel::unbounded();
+    let _midi_connection = match args.midi {
+        Some(port) => Some(midi::connect(port, events.clone(), 0)?),
+        None => None,
+    };
 
-    println!(
-        r#"
-        |   |   | |   |   |   |   | |   | |   |   |   |   | |   |   |
-        |   | S | | D |   |   | G | | H | | J |   |   | L | | ; |   |
-        |   |___| |___|   |   |___| |___| |___|   |   |___| |___|   |
-Note    |  C  |  D  |  E  |  F  |  G  |  A  |  B  |     |     |     |
-Key     |  Z  |  X  |  C  |  V  |  B  |  N  |  M  |  ,  |  .  |  /  |
-        |_____|_____|_____|_____|_____|_____|_____|_____|_____|_____|
-        "#
+    let instrument_names = instruments
+        .iter()
+        .map(|instrument| instrument.name())
+        .collect::<Vec<_>>();
+    // Not selectable from the keyboard
+    instruments.push(InstrumentType::from(Click::new()));
+    let click_id = instruments.len() - 1;
+    // The noise maker always outputs stereo
+    let recorder = args
+        .record
+        .as_ref()
+        .map(|path| RecorderHandle::new(path, 2, args.sample_rate));
+    // Each output gets its own noise maker, sharing the events and the status with the previous one
+    let open_output = |dt: FreqType| {
+        let noise_maker = NoiseMaker::new(
+            new_data(dt),
+            instruments.clone(),
+            events_receiver.clone(),
+            audio_status.clone(),
+            args.sample_rate,
+        );
+        let source: Box<dyn Source<Item = f32> + Send> = match &recorder {
+            Some(recorder) => Box::new(Recorder::with_handle(noise_maker, recorder.clone())),
+            None => Box::new(noise_maker),
+        };
+        if args.stdout {
+            Ok(Output::stdout(source))
+        } else {
+            Output::open(source, args.device.as_deref())
+        }
+    };
+    // The samples own stdout in `--stdout` mode
+    let mut ui: Box<dyn Write> = if args.stdout {
+        handle_interrupt();
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    let mut output = open_output(0.0)?;
+    let mut last_dt = 0.0;
+    let mut last_progress = Instant::now();
+    let mut reopen_attempts = 0;
+    let mut paused = false;
+
+    // Numbered like the instrument keys, a custom instrument and a sample come after the built in ones
+    let selectable = instrument_names.len().min(INSTRUMENT_KEYS.len());
+    let instrument_help = format!(
+        "1 - {} : {}",
+        selectable,
+        instrument_names[..selectable].join(", ")
     );
+    // The keys and their notes are shown with the status, so they follow the octave
+    writeln!(
+        ui,
+        r#"
+        [ / ] : octave down / up
+        - / = : volume down / up
+        Space : sustain pedal
+        Up/Dn : pitch bend up / down while held
+        Nm -/+: vibrato depth down / up
+        Nm //*: vibrato rate down / up
+        Nm 2/8: low-pass cutoff down / up, with --low-pass
+        Tab   : arpeggiator on / off
+        Enter : drum loop on / off
+        `     : metronome on / off
+        Back  : midi file play / stop
+        P     : mono with glide / poly
+        R     : legato / retrigger the releasing notes
+        T     : chord mode, cycles through off, major, minor, diminished and dominant 7th
+        Y     : glissando up the scale, or the major scale without --scale
+        Ins/Dl: attack up / down
+        Hm/End: decay up / down
+        PU/PD : sustain up / down
+        Rt/Lt : release up / down
+        {}
+        F1-F10: velocity 0.1 to 1.0
+        F11   : plot the waveforms of the last note
+        F12   : freeze the output
+        "#,
+        instrument_help
+    )?;
 
-    let octave = 4;
-    let octave_offset = 12 * (octave + 1); // octave is -1 based
+    let mut keyboard = KeyboardMapping::new(args.layout, args.base_note.into_u8(), args.keys);
+    let mut velocity: FreqType = 1.0;
+    let mut previous_keys = Vec::new();
+    // Note currently played by each key, so switching octave doesn't change held notes
+    let mut held_notes: Vec<Vec<u8>> = vec![Vec::new(); NUM_KEYBOARD_NOTES];
+    let mut chord: Option<ChordType> = None;
+    let mut arpeggiator: Option<Arpeggiator> = None;
+    let mut sequencer: Option<StepSequencer> = None;
+    let mut metronome: Option<Metronome> = None;
+    let mut midi_file_player = match &args.midi_file {
+        Some(path) => Some(MidiFilePlayer::new(midi_file::load(path, |channel| {
+            channel as usize % instrument_names.len()
+        })?)),
+        None => None,
+    };
+    let mut midi_file_playing = midi_file_player.is_some();
+    let mut glissando_player: Option<MidiFilePlayer> = None;
 
+    let device_state = DeviceState::new();
+    let mut debouncer = KeyDebouncer::new(DEBOUNCE_POLLS);
     loop {
-        let device_state = DeviceState::new();
-        let keys = device_state.get_keys();
-
-        for key in 0u8..=16u8 {
-            let is_pressed = is_key_pressed(key, &keys);
-
-            let note_id = key + octave_offset;
-
-            if let Ok(mut data) = data.lock() {
-                let dt = data.dt;
-                if let Some(note) = data.notes.iter_mut().find(|note| note.id == note_id) {
-                    if is_pressed {
-                        if note.off > note.on {
-                            note.on = dt;
-                            note.active = true;
-                        }
-                    } else if note.off < note.on {
-                        note.off = dt;
+        let poll_start = Instant::now();
+        let keys = debouncer.update(&device_state.get_keys());
+        let just_pressed = |key| keys.contains(&key) && !previous_keys.contains(&key);
+        let just_released = |key| !keys.contains(&key) && previous_keys.contains(&key);
+
+        if just_pressed(Keycode::LeftBracket) {
+            keyboard.shift_octaves(-1);
+        }
+        if just_pressed(Keycode::RightBracket) {
+            keyboard.shift_octaves(1);
+        }
+
+        if just_pressed(Keycode::Minus) || just_pressed(Keycode::Equal) {
+            let step = if just_pressed(Keycode::Minus) {
+                -VOLUME_STEP
+            } else {
+                VOLUME_STEP
+            };
+            settings.volume = (settings.volume + step).clamp(0.0, 1.0);
+            events.send(NoteEvent::MasterVolume {
+                volume: settings.volume,
+            })?;
+        }
+
+        let (depth_down, depth_up) = VIBRATO_DEPTH_KEYS;
+        if just_pressed(depth_down.clone()) || just_pressed(depth_up.clone()) {
+            let step = if just_pressed(depth_down.clone()) {
+                -VIBRATO_DEPTH_STEP
+            } else {
+                VIBRATO_DEPTH_STEP
+            };
+            settings.vibrato_depth = (settings.vibrato_depth + step).clamp(0.0, MAX_VIBRATO_DEPTH);
+            events.send(settings.vibrato())?;
+        }
+        let (rate_down, rate_up) = VIBRATO_RATE_KEYS;
+        if just_pressed(rate_down.clone()) || just_pressed(rate_up.clone()) {
+            let rate = if just_pressed(rate_down.clone()) {
+                settings.vibrato_rate / VIBRATO_RATE_FACTOR
+            } else {
+                settings.vibrato_rate * VIBRATO_RATE_FACTOR
+            };
+            settings.vibrato_rate = rate.clamp(MIN_VIBRATO_RATE, MAX_VIBRATO_RATE);
+            events.send(settings.vibrato())?;
+        }
+
+        let (cutoff_down, cutoff_up) = CUTOFF_KEYS;
+        if let Some(cutoff) = settings.cutoff.as_mut() {
+            if just_pressed(cutoff_down.clone()) || just_pressed(cutoff_up.clone()) {
+                let swept = if just_pressed(cutoff_down.clone()) {
+                    *cutoff / CUTOFF_FACTOR
+                } else {
+                    *cutoff * CUTOFF_FACTOR
+                };
+                *cutoff = swept.clamp(MIN_CUTOFF, MAX_CUTOFF);
+                events.send(NoteEvent::Cutoff { cutoff: *cutoff })?;
+            }
+        }
+
+        if just_pressed(MONO_KEY) {
+            settings.mono = !settings.mono;
+            events.send(NoteEvent::Mono {
+                enabled: settings.mono,
+            })?;
+        }
+
+        if just_pressed(CHORD_KEY) {
+            chord = match chord {
+                None => Some(ChordType::Major),
+                Some(ChordType::Major) => Some(ChordType::Minor),
+                Some(ChordType::Minor) => Some(ChordType::Diminished),
+                Some(ChordType::Diminished) => Some(ChordType::Dominant7),
+                Some(ChordType::Dominant7) => None,
+            };
+        }
+
+        if just_pressed(LEGATO_KEY) {
+            settings.legato = !settings.legato;
+            events.send(NoteEvent::Legato {
+                enabled: settings.legato,
+            })?;
+        }
+
+        for (stage, (decrease_key, increase_key)) in ENVELOPE_KEYS.iter().enumerate() {
+            let direction = if just_pressed(decrease_key.clone()) {
+                -1
+            } else if just_pressed(increase_key.clone()) {
+                1
+            } else {
+                continue;
+            };
+            let mut envelope = settings
+                .envelopes
+                .get(&instrument_id)
+                .copied()
+                .unwrap_or_else(|| instruments[instrument_id].envelope());
+            let scale = |time: FreqType| {
+                (time * ENVELOPE_TIME_FACTOR.powi(direction)).max(MIN_ENVELOPE_TIME)
+            };
+            match stage {
+                0 => envelope.attack_time = scale(envelope.attack_time),
+                1 => envelope.decay_time = scale(envelope.decay_time),
+                2 => {
+                    envelope.sustain_amplitude = (envelope.sustain_amplitude
+                        + direction as FreqType * SUSTAIN_STEP)
+                        .clamp(0.0, 1.0)
+                }
+                _ => envelope.release_time = scale(envelope.release_time),
+            }
+            settings.envelopes.insert(instrument_id, envelope);
+            events.send(NoteEvent::Envelope {
+                instrument_id,
+                envelope,
+            })?;
+            writeln!(
+                ui,
+                "\nAttack: {:.3}s Decay: {:.3}s Sustain: {:.2} Release: {:.3}s",
+                envelope.attack_time,
+                envelope.decay_time,
+                envelope.sustain_amplitude,
+                envelope.release_time
+            )?;
+        }
+
+        if just_pressed(WAVEFORM_KEY) {
+            let last_note = {
+                lock_status(&audio_status)
+                    .notes
+                    .iter()
+                    .filter(|note| note.active)
+                    .max_by(|a, b| a.on.total_cmp(&b.on))
+                    .map(|note| (note.instrument_id, note.freq))
+            };
+            if let Some((instrument_id, freq)) = last_note {
+                for (index, oscillator) in
+                    instruments[instrument_id].oscillators().iter().enumerate()
+                {
+                    let freq = freq * oscillator.freq_ratio();
+                    writeln!(ui, "\nOscillator {} at {:.2} Hz", index + 1, freq)?;
+                    writeln!(
+                        ui,
+                        "{}",
+                        plot::waveform(oscillator.wave(), freq, 64, 15, args.sample_rate)
+                    )?;
+                }
+            }
+        }
+
+        if just_pressed(FREEZE_KEY) {
+            settings.frozen = !settings.frozen;
+            events.send(NoteEvent::Freeze {
+                frozen: settings.frozen,
+            })?;
+        }
+
+        if just_pressed(SUSTAIN_KEY) {
+            events.send(NoteEvent::SustainPedal { down: true })?;
+        } else if just_released(SUSTAIN_KEY) {
+            events.send(NoteEvent::SustainPedal { down: false })?;
+        }
+
+        let bend_keys_changed = [BEND_UP_KEY, BEND_DOWN_KEY]
+            .iter()
+            .any(|key| just_pressed(key.clone()) || just_released(key.clone()));
+        if bend_keys_changed {
+            let mut semitones = 0.0;
+            if keys.contains(&BEND_UP_KEY) {
+                semitones += KEY_BEND_SEMITONES;
+            }
+            if keys.contains(&BEND_DOWN_KEY) {
+                semitones -= KEY_BEND_SEMITONES;
+            }
+            events.send(NoteEvent::PitchBend { semitones })?;
+        }
+
+        for (id, key) in INSTRUMENT_KEYS
+            .iter()
+            .enumerate()
+            .take(instrument_names.len())
+        {
+            if just_pressed(key.clone()) {
+                instrument_id = id;
+            }
+        }
+
+        for (index, key) in VELOCITY_KEYS.iter().enumerate() {
+            if just_pressed(key.clone()) {
+                velocity = (index + 1) as FreqType / VELOCITY_KEYS.len() as FreqType;
+            }
+        }
+
+        if just_pressed(ARPEGGIATOR_KEY) {
+            arpeggiator = match arpeggiator.take() {
+                Some(mut arpeggiator) => {
+                    for event in arpeggiator.stop() {
+                        events.send(event)?;
                     }
-                } else if is_pressed {
-                    data.notes.push(NoiseMakerNote {
-                        id: note_id,
-                        on: dt,
-                        off: 0.0,
-                        instrument_id: 0,
-                        active: true,
+                    None
+                }
+                None => Some(Arpeggiator::new(
+                    120.0,
+                    NoteDivision::Sixteenth,
+                    ArpPattern::Up,
+                    1,
+                )),
+            };
+        }
+
+        if just_pressed(SEQUENCER_KEY) {
+            sequencer = match sequencer.take() {
+                Some(mut sequencer) => {
+                    for event in sequencer.stop() {
+                        events.send(event)?;
+                    }
+                    None
+                }
+                None => {
+                    let kick = Some(Step {
+                        id: 60,
+                        velocity: 1.0,
                     });
+                    let mut sequencer = StepSequencer::new(
+                        120.0,
+                        (0..16)
+                            .map(|step| if step % 4 == 0 { kick } else { None })
+                            .collect(),
+                    );
+                    sequencer.instrument_id = drum_kick_id;
+                    Some(sequencer)
+                }
+            };
+        }
+
+        if just_pressed(METRONOME_KEY) {
+            metronome = match metronome.take() {
+                Some(mut metronome) => {
+                    for event in metronome.stop() {
+                        events.send(event)?;
+                    }
+                    None
+                }
+                None => {
+                    let mut metronome = Metronome::new(120.0, 4);
+                    metronome.instrument_id = click_id;
+                    Some(metronome)
+                }
+            };
+        }
+
+        if just_pressed(MIDI_FILE_KEY) {
+            if let Some(player) = &mut midi_file_player {
+                if midi_file_playing {
+                    for event in player.stop() {
+                        events.send(event)?;
+                    }
                 }
+                midi_file_playing = !midi_file_playing;
+            }
+        }
+
+        if just_pressed(GLISSANDO_KEY) {
+            glissando_player = match glissando_player.take() {
+                Some(mut player) => {
+                    for event in player.stop() {
+                        events.send(event)?;
+                    }
+                    None
+                }
+                None => Some(MidiFilePlayer::single_channel(glissando(
+                    keyboard.base(),
+                    keyboard.base().saturating_add(24).min(127),
+                    args.scale.unwrap_or(Scale::Major),
+                    args.scale_tonic.into_u8(),
+                    GLISSANDO_SECS,
+                    instrument_id,
+                    velocity,
+                ))),
+            };
+        }
+
+        for (key, offset) in keyboard.keys() {
+            let is_pressed = keys.contains(key);
 
-                print!(
-                    "\rNotes: {:?}                                          ",
-                    data.notes
-                        .iter()
-                        .map(|n| {
-                            let note = Note::from(n.id);
-                            format!("{} {:.2}", note, note.freq())
-                        })
-                        .collect::<Vec<_>>()
-                );
+            let held = &mut held_notes[*offset as usize];
+            if is_pressed && held.is_empty() {
+                let mut root_id = keyboard.base() + offset;
+                if let Some(scale) = args.scale {
+                    root_id = quantize_to_scale(root_id, scale, args.scale_tonic.into_u8());
+                }
+                *held = match chord {
+                    Some(chord) => chord_from_root(root_id, chord),
+                    None => vec![root_id],
+                };
+                // The arpeggiator plays the held notes itself
+                if arpeggiator.is_none() {
+                    for id in held.iter() {
+                        events.send(NoteEvent::On {
+                            id: *id,
+                            instrument_id,
+                            velocity,
+                        })?;
+                    }
+                }
+            } else if !is_pressed && !held.is_empty() {
+                // The whole chord is released with its key, but a note is only released once no
+                // other key holds it, overlapping chords and the scale can give two keys a note
+                let released = std::mem::take(held);
+                // The arpeggiator releases its own note
+                let arpeggiated = arpeggiator
+                    .as_ref()
+                    .and_then(|arpeggiator| arpeggiator.current_note());
+                for id in released {
+                    if Some(id) != arpeggiated
+                        && !held_notes.iter().flatten().any(|held| *held == id)
+                    {
+                        events.send(NoteEvent::off(id))?;
+                    }
+                }
             }
         }
 
-        if keys.contains(&Keycode::Escape) {
+        let dt = lock_status(&audio_status).dt;
+        // Time doesn't advance while frozen or paused even though the output is fine
+        if dt != last_dt || settings.frozen || paused {
+            last_dt = dt;
+            last_progress = Instant::now();
+            reopen_attempts = 0;
+        } else if last_progress.elapsed() > OUTPUT_TIMEOUT {
+            if reopen_attempts == MAX_OUTPUT_REOPEN_ATTEMPTS {
+                writeln!(ui, "\nThe audio output stopped responding, exiting")?;
+                if let Some(recorder) = &recorder {
+                    recorder.finalize()?;
+                }
+                break;
+            }
+            reopen_attempts += 1;
+            writeln!(
+                ui,
+                "\nThe audio output stopped responding, reopening the output device"
+            )?;
+            // Drop the dead stream before opening a new one
+            drop(output);
+            output = match open_output(dt) {
+                Ok(output) => output,
+                Err(err) => {
+                    writeln!(ui, "Could not reopen the audio output: {}", err)?;
+                    if let Some(recorder) = &recorder {
+                        recorder.finalize()?;
+                    }
+                    break;
+                }
+            };
+            // The notes playing in the dead output are lost, but the settings are kept
+            for event in settings.events() {
+                events.send(event)?;
+            }
+            last_progress = Instant::now();
+        }
+        if let Some(arpeggiator) = &mut arpeggiator {
+            let held = held_notes.iter().flatten().copied().collect::<Vec<_>>();
+            arpeggiator.set_held_notes(&held);
+            arpeggiator.instrument_id = instrument_id;
+            arpeggiator.velocity = velocity;
+            for event in arpeggiator.tick(dt) {
+                events.send(event)?;
+            }
+        }
+        if let Some(sequencer) = &mut sequencer {
+            for event in sequencer.tick(dt) {
+                events.send(event)?;
+            }
+        }
+        if let Some(metronome) = &mut metronome {
+            for event in metronome.tick(dt) {
+                events.send(event)?;
+            }
+        }
+        if let Some(player) = midi_file_player.as_mut().filter(|_| midi_file_playing) {
+            for event in player.tick(dt) {
+                events.send(event)?;
+            }
+            if player.is_finished() {
+                for event in player.stop() {
+                    events.send(event)?;
+                }
+                midi_file_playing = false;
+            }
+        }
+        if let Some(player) = &mut glissando_player {
+            for event in player.tick(dt) {
+                events.send(event)?;
+            }
+            if player.is_finished() {
+                for event in player.stop() {
+                    events.send(event)?;
+                }
+                glissando_player = None;
+            }
+        }
+
+        {
+            let audio = lock_status(&audio_status);
+            let status = format!(
+                "Instrument: {} Volume: {:.2} Velocity: {:.1} {}{}{}{}{}\nVoices: {} Peak: {:.2}\n{}\n{}",
+                instrument_names[instrument_id],
+                settings.volume,
+                velocity,
+                if settings.mono { "Mono" } else { "Poly" },
+                if settings.legato { " Legato" } else { "" },
+                if settings.vibrato_depth > 0.0 {
+                    format!(
+                        " Vibrato: {:.2}st at {:.1}Hz",
+                        settings.vibrato_depth, settings.vibrato_rate
+                    )
+                } else {
+                    String::new()
+                },
+                chord
+                    .map(|chord| format!(" Chord: {}", chord))
+                    .unwrap_or_default(),
+                if settings.frozen { " Frozen" } else { "" },
+                audio.active_voice_count,
+                audio.peak_amplitude,
+                keyboard.diagram(),
+                status::notes_table(&audio, &instruments),
+            );
+            // Clears the previous status and goes back to its first line, so it is redrawn in place
+            write!(
+                ui,
+                "\r\x1b[J{}\x1b[{}A\r",
+                status,
+                status.lines().count() - 1
+            )?;
+            ui.flush()?;
+        }
+
+        if keys.contains(&Keycode::Escape) || INTERRUPTED.load(Ordering::SeqCst) {
+            if let Some(recorder) = &recorder {
+                recorder.finalize()?;
+            }
             break;
         }
 
-        sink.play();
+        // Pausing stops the noise maker from running while silent. Time stops too, so the players
+        // waiting for the next note and the recording keep it running. The events sent by the keys
+        // may already be received but not in the status yet, so the output isn't paused after them
+        let events_sent = keys != previous_keys;
+        let clock_needed = arpeggiator.is_some()
+            || sequencer.is_some()
+            || metronome.is_some()
+            || midi_file_playing
+            || glissando_player.is_some()
+            || recorder.is_some();
+        paused = !clock_needed
+            && !events_sent
+            && events_receiver.is_empty()
+            && lock_status(&audio_status).idle;
+        output.set_paused(paused);
+        previous_keys = keys;
+        thread::sleep(POLL_INTERVAL.saturating_sub(poll_start.elapsed()));
     }
     Ok(())
 }
 
-fn is_key_pressed(key_id: u8, keys: &[Keycode]) -> bool {
-    match key_id {
-        0 if keys.contains(&Keycode::Z) => true,
-        1 if keys.contains(&Keycode::S) => true,
-        2 if keys.contains(&Keycode::X) => true,
-        3 if keys.contains(&Keycode::D) => true,
-        4 if keys.contains(&Keycode::C) => true,
-        5 if keys.contains(&Keycode::V) => true,
-        6 if keys.contains(&Keycode::G) => true,
-        7 if keys.contains(&Keycode::B) => true,
-        8 if keys.contains(&Keycode::H) => true,
-        9 if keys.contains(&Keycode::N) => true,
-        10 if keys.contains(&Keycode::J) => true,
-        11 if keys.contains(&Keycode::M) => true,
-        12 if keys.contains(&Keycode::Comma) => true,
-        13 if keys.contains(&Keycode::L) => true,
-        14 if keys.contains(&Keycode::Dot) => true,
-        15 if keys.contains(&Keycode::Semicolon) => true,
-        16 if keys.contains(&Keycode::Slash) => true,
-        _ => false,
+/// Plays each note for an equal part of `duration_secs` and writes the result to a wav file
+fn render_notes(
+    path: &Path,
+    instruments: Vec<InstrumentType>,
+    instrument_id: usize,
+    notes: &[Note],
+    duration_secs: FreqType,
+    seed: Option<u64>,
+    sample_rate: u32,
+) -> Result<()> {
+    let note_length = duration_secs / notes.len().max(1) as FreqType;
+    let events = notes
+        .iter()
+        .enumerate()
+        .flat_map(|(index, note)| {
+            let id = note.into_u8();
+            let on = index as FreqType * note_length;
+            [
+                (
+                    on,
+                    NoteEvent::On {
+                        id,
+                        instrument_id,
+                        velocity: 1.0,
+                    },
+                ),
+                (on + note_length, NoteEvent::off(id)),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let samples = synth_rs::render(instruments, &events, duration_secs, seed, sample_rate);
+    let recording = RecorderHandle::new(path, 2, sample_rate);
+    recording.push_samples(&samples);
+    recording.finalize()
+}
+
+/// Settings changed from the keyboard. The noise maker has its own copy, this one is sent again
+/// to the noise maker of a reopened output
+struct Settings {
+    volume: FreqType,
+    vibrato_depth: FreqType,
+    vibrato_rate: FreqType,
+    mono: bool,
+    legato: bool,
+    frozen: bool,
+    envelopes: HashMap<usize, EnvelopeADSR>,
+    /// Cutoff of the `--low-pass` filter, `None` without it
+    cutoff: Option<FreqType>,
+}
+
+impl Settings {
+    fn new(data: &NoiseMakerData) -> Self {
+        Self {
+            volume: data.master_volume.target(),
+            vibrato_depth: data.vibrato.depth,
+            vibrato_rate: data.vibrato.rate,
+            mono: data.mono,
+            legato: data.legato,
+            frozen: data.frozen,
+            envelopes: HashMap::new(),
+            cutoff: data.effects.iter().find_map(|effect| match effect {
+                EffectType::LowPassFilter(filter) => Some(filter.cutoff()),
+                _ => None,
+            }),
+        }
+    }
+
+    fn vibrato(&self) -> NoteEvent {
+        NoteEvent::Vibrato {
+            depth: self.vibrato_depth,
+            rate: self.vibrato_rate,
+        }
+    }
+
+    /// Events giving these settings to a new noise maker
+    fn events(&self) -> Vec<NoteEvent> {
+        let mut events = vec![
+            NoteEvent::MasterVolume {
+                volume: self.volume,
+            },
+            self.vibrato(),
+            NoteEvent::Mono { enabled: self.mono },
+            NoteEvent::Legato {
+                enabled: self.legato,
+            },
+            NoteEvent::Freeze {
+                frozen: self.frozen,
+            },
+        ];
+        events.extend(self.envelopes.iter().map(|(&instrument_id, &envelope)| {
+            NoteEvent::Envelope {
+                instrument_id,
+                envelope,
+            }
+        }));
+        events.extend(self.cutoff.map(|cutoff| NoteEvent::Cutoff { cutoff }));
+        events
+    }
+}
+
+/// Where the samples go
+enum Output {
+    /// Sink playing on an output device, the stream has to be kept alive for it to play
+    Device { _stream: OutputStream, sink: Sink },
+    /// Thread writing the samples to stdout in real time, stopped when dropped
+    Stdout {
+        stop: Arc<AtomicBool>,
+        writer: Option<JoinHandle<()>>,
+    },
+}
+
+impl Output {
+    /// Frames written to stdout at once, small enough to keep the latency low
+    const STDOUT_CHUNK_FRAMES: usize = 512;
+
+    /// Uses the default device when `device` is `None`, see `find_output_device`
+    fn open<S>(source: S, device: Option<&str>) -> Result<Self>
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let (stream, stream_handle) = match device {
+            Some(device) => OutputStream::try_from_device(&find_output_device(device)?)?,
+            None => OutputStream::try_default()?,
+        };
+        let sink = Sink::try_new(&stream_handle)?;
+        sink.append(source);
+        Ok(Self::Device {
+            _stream: stream,
+            sink,
+        })
+    }
+
+    /// Writes the samples as raw little endian f32, paced to the sample rate of the source so the
+    /// keyboard plays in real time. Stops when stdout is closed
+    fn stdout<S>(mut source: S) -> Self
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let samples_per_sec = source.sample_rate() as f64 * source.channels() as f64;
+                let chunk_len = Self::STDOUT_CHUNK_FRAMES * source.channels() as usize;
+                let mut stdout = io::stdout();
+                let mut bytes = Vec::with_capacity(chunk_len * 4);
+                let mut written = 0_u64;
+                let start = Instant::now();
+                while !stop.load(Ordering::SeqCst) {
+                    bytes.clear();
+                    for sample in source.by_ref().take(chunk_len) {
+                        bytes.extend_from_slice(&sample.to_le_bytes());
+                    }
+                    if stdout
+                        .write_all(&bytes)
+                        .and_then(|_| stdout.flush())
+                        .is_err()
+                    {
+                        break;
+                    }
+                    written += chunk_len as u64;
+                    let ahead = Duration::from_secs_f64(written as f64 / samples_per_sec)
+                        .saturating_sub(start.elapsed());
+                    thread::sleep(ahead);
+                }
+            })
+        };
+        Self::Stdout {
+            stop,
+            writer: Some(writer),
+        }
+    }
+
+    /// The stdout stream keeps going so the player reading it doesn't run dry
+    fn set_paused(&self, paused: bool) {
+        match self {
+            Self::Device { sink, .. } if paused => sink.pause(),
+            Self::Device { sink, .. } => sink.play(),
+            Self::Stdout { .. } => {}
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Self::Stdout { stop, writer } = self {
+            stop.store(true, Ordering::SeqCst);
+            if let Some(writer) = writer.take() {
+                let _ = writer.join();
+            }
+        }
+    }
+}
+
+fn output_devices() -> Result<Vec<cpal::Device>> {
+    Ok(cpal::default_host().output_devices()?.collect())
+}
+
+/// Finds an output device from its index in `--list-devices` or from its name
+fn find_output_device(device: &str) -> Result<cpal::Device> {
+    let devices = output_devices()?;
+    let found = match device.parse::<usize>() {
+        Ok(index) => devices.into_iter().nth(index),
+        Err(_) => devices
+            .into_iter()
+            .find(|candidate| candidate.name().is_ok_and(|name| name == device)),
+    };
+    found.ok_or_else(|| anyhow!("No audio output device {}", device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::TAU;
+
+    /// Frequency with the most energy in the left channel, searched in 5Hz steps
+    fn peak_frequency(samples: &[FreqType], sample_rate: u32) -> FreqType {
+        let magnitude = |hertz: FreqType| {
+            let (re, im) = samples
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(re, im), (i, sample)| {
+                    let phase = TAU * hertz * i as FreqType / sample_rate as FreqType;
+                    (re + sample * phase.cos(), im + sample * phase.sin())
+                });
+            re * re + im * im
+        };
+        (20..400)
+            .map(|step| step as FreqType * 5.0)
+            .max_by(|a, b| magnitude(*a).partial_cmp(&magnitude(*b)).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn rendered_a4_peaks_at_440_hz() {
+        let path = std::env::temp_dir().join(format!("synth_rs_render_{}.wav", std::process::id()));
+        let sample_rate = 44100;
+        render_notes(
+            &path,
+            InstrumentType::builtin(),
+            0,
+            &["A4".parse().unwrap()],
+            0.5,
+            Some(1),
+            sample_rate,
+        )
+        .unwrap();
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, sample_rate);
+        let left = reader
+            .samples::<i16>()
+            .step_by(2)
+            .map(|sample| sample.unwrap() as FreqType / i16::MAX as FreqType)
+            .collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(left.len(), sample_rate as usize / 2);
+        // After the attack, a tenth of a second gives a 10Hz resolution
+        let window = &left[sample_rate as usize / 5..sample_rate as usize * 3 / 10];
+        assert_eq!(peak_frequency(window, sample_rate), 440.0);
     }
 }
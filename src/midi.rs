@@ -0,0 +1,133 @@
+use crate::noise_maker::{FreqType, NoteEvent};
+#[cfg(feature = "midi")]
+use anyhow::{anyhow, Result};
+#[cfg(feature = "midi")]
+use crossbeam_channel::Sender;
+#[cfg(feature = "midi")]
+use midir::{MidiInput, MidiInputConnection};
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PITCH_BEND: u8 = 0xE0;
+const SUSTAIN_PEDAL: u8 = 64;
+/// Pitch bend at the ends of the pitch wheel, the usual default of synths
+const PITCH_BEND_RANGE_SEMITONES: FreqType = 2.0;
+
+/// Turns raw midi bytes into note events, remembering the running status between messages
+pub struct MidiParser {
+    running_status: Option<u8>,
+    data: Vec<u8>,
+    instrument_id: usize,
+}
+
+impl MidiParser {
+    pub fn new(instrument_id: usize) -> Self {
+        Self {
+            running_status: None,
+            data: Vec::with_capacity(2),
+            instrument_id,
+        }
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<NoteEvent> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            if byte >= 0xF8 {
+                // Realtime messages can be interleaved anywhere and don't affect the running status
+                continue;
+            }
+            if byte & 0x80 != 0 {
+                // System common messages cancel the running status
+                self.running_status = if byte < 0xF0 { Some(byte) } else { None };
+                self.data.clear();
+                continue;
+            }
+            let status = match self.running_status {
+                Some(status) => status,
+                None => continue,
+            };
+            self.data.push(byte);
+            if self.data.len() < data_len(status) {
+                continue;
+            }
+            if let Some(event) = self.note_event(status) {
+                events.push(event);
+            }
+            self.data.clear();
+        }
+        events
+    }
+
+    fn note_event(&self, status: u8) -> Option<NoteEvent> {
+        let id = self.data[0];
+        match status & 0xF0 {
+            NOTE_ON if self.data[1] > 0 => Some(NoteEvent::On {
+                id,
+                instrument_id: self.instrument_id,
+                velocity: self.data[1] as FreqType / 127.0,
+            }),
+            // A note on with a velocity of 0 is a note off, without a release velocity
+            NOTE_ON => Some(NoteEvent::off(id)),
+            NOTE_OFF => Some(NoteEvent::Off {
+                id,
+                velocity: self.data[1] as FreqType / 127.0,
+            }),
+            CONTROL_CHANGE if self.data[0] == SUSTAIN_PEDAL => Some(NoteEvent::SustainPedal {
+                down: self.data[1] >= 64,
+            }),
+            PITCH_BEND => {
+                // 14 bits value centered on 8192, least significant bits first
+                let value = (self.data[0] as i16 | (self.data[1] as i16) << 7) - 8192;
+                Some(NoteEvent::PitchBend {
+                    semitones: value as FreqType / 8192.0 * PITCH_BEND_RANGE_SEMITONES,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Number of data bytes following a channel message status
+pub(crate) fn data_len(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+#[cfg(feature = "midi")]
+pub fn port_names() -> Result<Vec<String>> {
+    let input = MidiInput::new("synth_rs")?;
+    input
+        .ports()
+        .iter()
+        .map(|port| Ok(input.port_name(port)?))
+        .collect()
+}
+
+/// Sends the note events of the midi input port to the noise maker, as long as the connection is alive
+#[cfg(feature = "midi")]
+pub fn connect(
+    port_index: usize,
+    events: Sender<NoteEvent>,
+    instrument_id: usize,
+) -> Result<MidiInputConnection<MidiParser>> {
+    let input = MidiInput::new("synth_rs")?;
+    let ports = input.ports();
+    let port = ports
+        .get(port_index)
+        .ok_or_else(|| anyhow!("No midi input port at index {}", port_index))?;
+    input
+        .connect(
+            port,
+            "synth_rs-input",
+            move |_, message, parser| {
+                for event in parser.parse(message) {
+                    let _ = events.send(event);
+                }
+            },
+            MidiParser::new(instrument_id),
+        )
+        .map_err(|err| anyhow!("Failed to connect to midi input: {}", err))
+}
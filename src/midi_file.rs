@@ -0,0 +1,387 @@
+use crate::{
+    midi::{data_len, MidiParser},
+    noise_maker::{FreqType, NoteEvent},
+};
+use anyhow::{anyhow, Result};
+use std::{fs, path::Path};
+
+/// Tempo of a file without tempo events, 120 bpm
+const DEFAULT_MICROS_PER_QUARTER: u32 = 500_000;
+
+/// Loads the note events of a standard midi file, see [`parse`]
+pub fn load(
+    path: impl AsRef<Path>,
+    instrument_for_channel: impl Fn(u8) -> usize,
+) -> Result<Vec<(FreqType, u8, NoteEvent)>> {
+    parse(&fs::read(path)?, instrument_for_channel)
+}
+
+/// Parses the note events of every track of a standard midi file with their time in seconds and
+/// their midi channel, sorted by time. `instrument_for_channel` picks the instrument playing each
+/// midi channel.
+pub fn parse(
+    bytes: &[u8],
+    instrument_for_channel: impl Fn(u8) -> usize,
+) -> Result<Vec<(FreqType, u8, NoteEvent)>> {
+    let mut reader = Reader { bytes, position: 0 };
+    let (id, header) = reader.chunk()?;
+    if id != b"MThd" || header.len() < 6 {
+        return Err(anyhow!("Not a standard midi file"));
+    }
+    let num_tracks = u16::from_be_bytes([header[2], header[3]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+
+    // Events of every track with their tick, merged later so the tempo applies to all the tracks
+    let mut tempo_changes = Vec::new();
+    let mut channel_messages = Vec::new();
+    let mut tracks_read = 0;
+    while tracks_read < num_tracks && !reader.is_empty() {
+        // Unknown chunks have to be skipped
+        let (id, track) = reader.chunk()?;
+        if id == b"MTrk" {
+            read_track(track, &mut tempo_changes, &mut channel_messages)?;
+            tracks_read += 1;
+        }
+    }
+    // Stable sorts keep the order of the events happening on the same tick
+    tempo_changes.sort_by_key(|(tick, _)| *tick);
+    channel_messages.sort_by_key(|(tick, _)| *tick);
+
+    let mut parsers = (0..16)
+        .map(|channel| MidiParser::new(instrument_for_channel(channel)))
+        .collect::<Vec<_>>();
+    let mut tempo_changes = tempo_changes.into_iter().peekable();
+    let mut micros_per_quarter = DEFAULT_MICROS_PER_QUARTER;
+    let mut last_tick = 0;
+    let mut time = 0.0;
+    let mut events = Vec::new();
+    for (tick, message) in channel_messages {
+        while let Some((change_tick, micros)) = tempo_changes.next_if(|(change, _)| *change <= tick)
+        {
+            time += seconds_per_tick(division, micros_per_quarter)
+                * (change_tick - last_tick) as FreqType;
+            last_tick = change_tick;
+            micros_per_quarter = micros;
+        }
+        time += seconds_per_tick(division, micros_per_quarter) * (tick - last_tick) as FreqType;
+        last_tick = tick;
+        let channel = message[0] & 0x0F;
+        for event in parsers[channel as usize].parse(&message) {
+            events.push((time, channel, event));
+        }
+    }
+    Ok(events)
+}
+
+fn seconds_per_tick(division: u16, micros_per_quarter: u32) -> FreqType {
+    if division & 0x8000 != 0 {
+        // SMPTE timing, frames per second and ticks per frame don't depend on the tempo
+        let frames_per_second = -((division >> 8) as u8 as i8) as FreqType;
+        let ticks_per_frame = (division & 0xFF) as FreqType;
+        1.0 / (frames_per_second * ticks_per_frame)
+    } else {
+        micros_per_quarter as FreqType / 1_000_000.0 / division.max(1) as FreqType
+    }
+}
+
+/// Collects the tempo changes and the channel messages of a track with their absolute tick
+fn read_track(
+    track: &[u8],
+    tempo_changes: &mut Vec<(u64, u32)>,
+    channel_messages: &mut Vec<(u64, Vec<u8>)>,
+) -> Result<()> {
+    let mut reader = Reader {
+        bytes: track,
+        position: 0,
+    };
+    let mut tick = 0;
+    let mut running_status = None;
+    while !reader.is_empty() {
+        tick += reader.variable_length()? as u64;
+        let first = reader.byte()?;
+        match first {
+            0xFF => {
+                let kind = reader.byte()?;
+                let len = reader.variable_length()? as usize;
+                let data = reader.take(len)?;
+                if kind == 0x51 && data.len() == 3 {
+                    tempo_changes.push((tick, u32::from_be_bytes([0, data[0], data[1], data[2]])));
+                }
+            }
+            0xF0 | 0xF7 => {
+                let len = reader.variable_length()? as usize;
+                reader.take(len)?;
+            }
+            _ => {
+                // Data bytes reuse the status of the previous channel message
+                let (status, first_data) = if first & 0x80 != 0 {
+                    (first, None)
+                } else {
+                    let status =
+                        running_status.ok_or_else(|| anyhow!("Midi data byte without a status"))?;
+                    (status, Some(first))
+                };
+                running_status = Some(status);
+                let mut message = vec![status];
+                message.extend(first_data);
+                while message.len() < data_len(status) + 1 {
+                    message.push(reader.byte()?);
+                }
+                channel_messages.push((tick, message));
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + len)
+            .ok_or_else(|| anyhow!("Unexpected end of midi file"))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Quantity stored 7 bits per byte, the high bit is set on every byte but the last one
+    fn variable_length(&mut self) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Midi variable length quantity is too long"))
+    }
+
+    /// Reads a chunk made of a 4 bytes id, a length and the data, returning the id and the data
+    fn chunk(&mut self) -> Result<(&'a [u8], &'a [u8])> {
+        let id = self.take(4)?;
+        let len = self.take(4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        Ok((id, self.take(len)?))
+    }
+}
+
+/// Plays the events of a midi file in real time, following the synth time
+pub struct MidiFilePlayer {
+    events: Vec<(FreqType, u8, NoteEvent)>,
+    position: usize,
+    start_time: Option<FreqType>,
+    /// Channels and notes started and not released yet, released when stopping
+    playing: Vec<(u8, u8)>,
+}
+
+impl MidiFilePlayer {
+    /// `events` must be sorted by time, like the ones returned by [`load`]
+    pub fn new(events: Vec<(FreqType, u8, NoteEvent)>) -> Self {
+        Self {
+            events,
+            position: 0,
+            start_time: None,
+            playing: Vec::new(),
+        }
+    }
+
+    /// Plays timed events all on the same channel, like the ones of [`glissando`]
+    ///
+    /// [`glissando`]: crate::sequencer::glissando
+    pub fn single_channel(events: Vec<(FreqType, NoteEvent)>) -> Self {
+        Self::new(
+            events
+                .into_iter()
+                .map(|(time, event)| (time, 0, event))
+                .collect(),
+        )
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.events.len()
+    }
+
+    /// Returns the note events to send at the synth time `dt`
+    pub fn tick(&mut self, dt: FreqType) -> Vec<NoteEvent> {
+        let start_time = *self.start_time.get_or_insert(dt);
+        let mut events = Vec::new();
+        while let Some(&(time, channel, event)) = self.events.get(self.position) {
+            if start_time + time > dt {
+                break;
+            }
+            self.position += 1;
+            match event {
+                NoteEvent::On { id, .. } if !self.playing.contains(&(channel, id)) => {
+                    self.playing.push((channel, id));
+                }
+                NoteEvent::Off { id, .. } => {
+                    self.playing.retain(|playing| *playing != (channel, id));
+                    // The synth has a single note per id, released once no channel holds it
+                    if self.playing.iter().any(|(_, playing)| *playing == id) {
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            events.push(event);
+        }
+        events
+    }
+
+    /// Releases the playing notes and rewinds to the beginning
+    pub fn stop(&mut self) -> Vec<NoteEvent> {
+        self.position = 0;
+        self.start_time = None;
+        let mut ids = self.playing.drain(..).map(|(_, id)| id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.into_iter()
+            .map(NoteEvent::off)
+            .chain([
+                NoteEvent::SustainPedal { down: false },
+                NoteEvent::PitchBend { semitones: 0.0 },
+            ])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Standard midi file with 480 ticks per quarter note and these tracks
+    fn midi_file(tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend(6_u32.to_be_bytes());
+        bytes.extend(1_u16.to_be_bytes());
+        bytes.extend((tracks.len() as u16).to_be_bytes());
+        bytes.extend(480_u16.to_be_bytes());
+        for track in tracks {
+            bytes.extend(b"MTrk");
+            bytes.extend((track.len() as u32).to_be_bytes());
+            bytes.extend(*track);
+        }
+        bytes
+    }
+
+    fn note(event: &NoteEvent) -> (bool, u8) {
+        match *event {
+            NoteEvent::On { id, .. } => (true, id),
+            NoteEvent::Off { id, .. } => (false, id),
+            _ => panic!("not a note event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn parses_the_notes_with_their_time() {
+        // A quarter note at 120 bpm, then the second note on uses the running status and a
+        // velocity of 0 as its note off, 0x83 0x60 is 480 as a variable length quantity
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100, 0x83, 0x60, 0x80, 60, 64, 0x00, 0x90, 62, 127, 0x83, 0x60, 62, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let events = parse(&midi_file(&[track]), |_| 0).unwrap();
+        let notes = events
+            .iter()
+            .map(|(time, channel, event)| (*time, *channel, note(event)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            notes,
+            [
+                (0.0, 0, (true, 60)),
+                (0.5, 0, (false, 60)),
+                (0.5, 0, (true, 62)),
+                (1.0, 0, (false, 62)),
+            ]
+        );
+        match events[0].2 {
+            NoteEvent::On { velocity, .. } => assert!((velocity - 100.0 / 127.0).abs() < 1e-9),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn applies_the_tempo_changes_to_every_track() {
+        // The first track halves the tempo after a quarter note, 0x0F4240 is a second per quarter
+        let tempo: &[u8] = &[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x83, 0x60, 0xFF, 0x51, 0x03, 0x0F, 0x42,
+            0x40, 0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let notes: &[u8] = &[0x87, 0x40, 0x91, 60, 100, 0x00, 0xFF, 0x2F, 0x00];
+        let events = parse(&midi_file(&[tempo, notes]), |channel| channel as usize * 10).unwrap();
+        assert_eq!(events.len(), 1);
+        let (time, channel, event) = events[0];
+        // 480 ticks at 120 bpm, then 480 ticks at 60 bpm
+        assert!((time - 1.5).abs() < 1e-9, "{}", time);
+        assert_eq!(channel, 1);
+        match event {
+            NoteEvent::On { instrument_id, .. } => assert_eq!(instrument_id, 10),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn skips_unknown_chunks() {
+        let track: &[u8] = &[0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00];
+        let mut bytes = midi_file(&[]);
+        bytes[10..12].copy_from_slice(&1_u16.to_be_bytes());
+        bytes.extend(b"XTRA");
+        bytes.extend(2_u32.to_be_bytes());
+        bytes.extend([1, 2]);
+        bytes.extend(midi_file(&[track])[14..].iter());
+        let events = parse(&bytes, |_| 0).unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        assert!(parse(b"MThd", |_| 0).is_err());
+        let mut bytes = midi_file(&[&[0x00, 0x90, 60, 100]]);
+        bytes.truncate(bytes.len() - 1);
+        assert!(parse(&bytes, |_| 0).is_err());
+    }
+
+    #[test]
+    fn a_note_held_by_another_channel_is_not_released() {
+        let on = |id| NoteEvent::On {
+            id,
+            instrument_id: 0,
+            velocity: 1.0,
+        };
+        let mut player = MidiFilePlayer::new(vec![
+            (0.0, 0, on(60)),
+            (0.0, 1, on(60)),
+            (1.0, 0, NoteEvent::off(60)),
+            (2.0, 1, NoteEvent::off(60)),
+        ]);
+        assert_eq!(player.tick(0.0).len(), 2);
+        assert!(player.tick(1.0).is_empty());
+        let events = player.tick(2.0);
+        assert_eq!(events.len(), 1);
+        assert_eq!(note(&events[0]), (false, 60));
+
+        // Restarted, stopping releases the note held by both channels once
+        player.stop();
+        player.tick(10.0);
+        let released = player
+            .stop()
+            .iter()
+            .filter(|event| matches!(event, NoteEvent::Off { .. }))
+            .count();
+        assert_eq!(released, 1);
+    }
+}
@@ -1,19 +1,52 @@
-use crate::instruments::{Instrument, InstrumentType};
+use crate::{
+    effects::{BiquadFilter, DcBlocker, Effect, EffectType},
+    instruments::{Instrument, InstrumentType},
+    lfo::{Lfo, LfoTarget},
+    smoothed_param::SmoothedParam,
+    tuning::{EqualTemperament, Tuning, TuningType},
+};
 use core::f32;
+use crossbeam_channel::Receiver;
+use fastrand::Rng;
+use once_cell::sync::Lazy;
+#[cfg(feature = "audio")]
 use rodio::source::Source;
+use serde::{Deserialize, Serialize};
 use std::{
-    f64::consts::{FRAC_2_PI, PI, TAU},
-    sync::{Arc, Mutex},
-    time::Duration,
+    cmp::Ordering,
+    collections::HashMap,
+    f64::consts::{FRAC_2_PI, FRAC_PI_4, PI, SQRT_2, TAU},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError},
 };
 
 pub type FreqType = f64;
 
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+/// Time for the notes to follow a change of pitch bend, avoids jumps in pitch
+const PITCH_BEND_SMOOTHING_SECS: FreqType = 0.01;
+/// Distance from `NoiseMakerData::keyboard_pan_center` in semitones at which a note is fully
+/// panned with a `keyboard_pan_spread` of 1.0
+const KEYBOARD_PAN_RANGE: FreqType = 48.0;
+/// Time for `NoiseMakerData::peak_amplitude` to fall by about 63% after a peak
+const PEAK_DECAY_SECS: FreqType = 0.3;
+/// Time for the gain of the mix to follow a change in the number of voices, avoids pumping
+const MIX_GAIN_SMOOTHING_SECS: FreqType = 0.05;
+/// Peak below which the output is considered silent, -80dB
+const IDLE_PEAK: FreqType = 0.0001;
+/// Release velocity of the note offs without one, 64 in midi, keeps the release time of the envelope
+pub const DEFAULT_OFF_VELOCITY: FreqType = 0.5;
+
 /// Converts frequency (Hz) to angular velocity
-fn w(hertz: FreqType) -> FreqType {
+pub fn w(hertz: FreqType) -> FreqType {
     hertz * 2.0 * PI
 }
 
+/// Equal power panning, -1.0 is fully left and 1.0 is fully right
+pub fn pan_gains(pan: FreqType) -> (FreqType, FreqType) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
 #[derive(Debug)]
 pub struct Note {
     pub id: u8,
@@ -21,6 +54,88 @@ pub struct Note {
     pub off: FreqType,
     pub active: bool,
     pub instrument_id: usize,
+    pub velocity: FreqType,
+    /// Released while the sustain pedal was down, will be turned off when the pedal is lifted
+    pub sustained: bool,
+    pub glide: Option<Glide>,
+    /// Current pitch bend in semitones, following the global pitch bend smoothly
+    pub pitch_bend: FreqType,
+    /// Phase added to the fundamental by the pitch bend so far
+    pub bend_phase: FreqType,
+    /// Phase added to the fundamental by the pitch LFOs of the instrument so far
+    pub lfo_phase: FreqType,
+    /// Replaces the envelope of the instrument, set from `NoiseMakerData::set_envelope`
+    pub envelope: Option<EnvelopeADSR>,
+    /// Release velocity from 0.0 to 1.0, a higher velocity releases faster
+    pub off_velocity: FreqType,
+    /// Envelope amplitude of the last sample
+    pub amplitude: FreqType,
+    /// Amplitude the attack starts from, not 0.0 when retriggered in legato
+    pub attack_from: FreqType,
+    /// Vibrating string of plucked string instruments, plucked on the first sample
+    pub string: Option<KarplusStrong>,
+    /// Generates the noise of this note, seeded by the noise maker so seeded output is reproducible
+    pub rng: Rng,
+    /// Filter state of each pink noise oscillator, indexed like the instrument oscillators
+    pub pink_noise: Vec<PinkNoise>,
+    /// Left and right state of the filter of the instrument, created on the first sample
+    pub filter: Option<(BiquadFilter, BiquadFilter)>,
+}
+
+/// Frequency slide from a previous note to the current note id
+#[derive(Clone, Copy, Debug)]
+pub struct Glide {
+    pub from_freq: FreqType,
+    /// When the glide started
+    pub start: FreqType,
+    /// Phase of the fundamental when the glide started, so the wave stays continuous
+    pub start_phase: FreqType,
+    pub time: FreqType,
+}
+
+impl Note {
+    /// Envelope of the note, `instrument_envelope` unless it is replaced, with the release time
+    /// scaled by the release velocity. Twice as long at 0.0 and half as long at 1.0
+    pub fn envelope_or(&self, instrument_envelope: EnvelopeADSR) -> EnvelopeADSR {
+        let mut envelope = self.envelope.unwrap_or(instrument_envelope);
+        envelope.release_time *= 2.0_f64.powf(1.0 - 2.0 * self.off_velocity);
+        envelope
+    }
+
+    /// Amplitude of `envelope` at `dt`, remembered so a legato retrigger can start from it
+    pub fn envelope_amplitude(&mut self, envelope: &EnvelopeADSR, dt: FreqType) -> FreqType {
+        self.amplitude = envelope.amplitude_from(dt, self.on, self.off, self.attack_from);
+        self.amplitude
+    }
+
+    /// Frequency of the fundamental at `dt`, following the glide if there is one
+    pub fn freq(&self, dt: FreqType, tuning: &TuningType) -> FreqType {
+        let freq = tuning.id_to_freq(self.id);
+        match self.glide {
+            Some(glide) if dt - glide.start < glide.time => {
+                let progress = (dt - glide.start) / glide.time;
+                glide.from_freq + (freq - glide.from_freq) * progress
+            }
+            _ => freq,
+        }
+    }
+
+    /// Phase of the fundamental at `dt`, the frequency is integrated over the glide
+    pub fn phase(&self, dt: FreqType, tuning: &TuningType) -> FreqType {
+        let freq = tuning.id_to_freq(self.id);
+        match self.glide {
+            Some(glide) => {
+                let elapsed = dt - glide.start;
+                let ramp = if elapsed < glide.time {
+                    elapsed * elapsed / (2.0 * glide.time)
+                } else {
+                    elapsed - glide.time / 2.0
+                };
+                glide.start_phase + w(glide.from_freq) * elapsed + w(freq - glide.from_freq) * ramp
+            }
+            None => w(freq) * (dt - self.on),
+        }
+    }
 }
 
 impl Default for Note {
@@ -31,53 +146,304 @@ impl Default for Note {
             off: 0.0,
             active: false,
             instrument_id: 0,
+            velocity: 1.0,
+            sustained: false,
+            glide: None,
+            pitch_bend: 0.0,
+            bend_phase: 0.0,
+            lfo_phase: 0.0,
+            envelope: None,
+            off_velocity: DEFAULT_OFF_VELOCITY,
+            amplitude: 0.0,
+            attack_from: 0.0,
+            string: None,
+            rng: Rng::new(),
+            pink_noise: Vec::new(),
+            filter: None,
         }
     }
 }
 
-#[derive(Clone, Copy)]
-#[allow(dead_code)]
+/// Paul Kellet's filter turning white noise into pink noise,
+/// see https://www.firstpr.com.au/dsp/pink-noise/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PinkNoise {
+    b: [FreqType; 7],
+}
+
+impl PinkNoise {
+    pub fn filter(&mut self, white: FreqType) -> FreqType {
+        let b = &mut self.b;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b.iter().sum::<FreqType>() + white * 0.5362;
+        b[6] = white * 0.115926;
+        // Brings the peaks back to about -1 to 1
+        pink * 0.11
+    }
+}
+
+/// Karplus-Strong string, a delay line excited by a noise burst and fed back through an averaging filter,
+/// see https://en.wikipedia.org/wiki/Karplus%E2%80%93Strong_string_synthesis
+#[derive(Clone, Debug, Default)]
+pub struct KarplusStrong {
+    buffer: Vec<FreqType>,
+    position: usize,
+}
+
+impl KarplusStrong {
+    /// The averaging filter delays the loop by half a sample, which is taken out of the buffer
+    pub fn pluck(freq: FreqType, rng: &Rng, sample_rate: u32) -> Self {
+        let period = sample_rate as FreqType / freq.max(1.0);
+        let len = ((period - 0.5).round() as usize).max(2);
+        Self {
+            buffer: (0..len).map(|_| rng.f64() * 2.0 - 1.0).collect(),
+            position: 0,
+        }
+    }
+
+    /// `decay` is the gain of every round trip, a lower value mutes the string faster
+    pub fn next(&mut self, decay: FreqType) -> FreqType {
+        let len = self.buffer.len();
+        let current = self.buffer[self.position];
+        let next = self.buffer[(self.position + 1) % len];
+        self.buffer[self.position] = decay * (current + next) / 2.0;
+        self.position = (self.position + 1) % len;
+        current
+    }
+}
+
+/// Sent by the input thread to the audio thread, which applies them at its current `dt`
+#[derive(Clone, Copy, Debug)]
+pub enum NoteEvent {
+    On {
+        id: u8,
+        instrument_id: usize,
+        velocity: FreqType,
+    },
+    /// `velocity` is the release velocity, see `Note::off_velocity`
+    Off {
+        id: u8,
+        velocity: FreqType,
+    },
+    SustainPedal {
+        down: bool,
+    },
+    /// Bends every note, 0.0 is the normal pitch
+    PitchBend {
+        semitones: FreqType,
+    },
+    /// Target of the smoothed master volume
+    MasterVolume {
+        volume: FreqType,
+    },
+    /// Depth in semitones and rate in hertz of the global vibrato
+    Vibrato {
+        depth: FreqType,
+        rate: FreqType,
+    },
+    Mono {
+        enabled: bool,
+    },
+    Legato {
+        enabled: bool,
+    },
+    /// Replaces the envelope of an instrument, see `NoiseMakerData::set_envelope`
+    Envelope {
+        instrument_id: usize,
+        envelope: EnvelopeADSR,
+    },
+    /// Repeats the last frame without advancing time, see `NoiseMakerData::frozen`
+    Freeze {
+        frozen: bool,
+    },
+    /// Cutoff in Hz of the filters of the master effects, like the `--low-pass` filter
+    Cutoff {
+        cutoff: FreqType,
+    },
+}
+
+impl NoteEvent {
+    /// Note off with the default release velocity
+    pub fn off(id: u8) -> Self {
+        Self::Off {
+            id,
+            velocity: DEFAULT_OFF_VELOCITY,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum WaveType {
     Sine,
     Square,
     Triangle,
     SawSlow,
     SawFast,
+    /// White noise, every frequency at the same level
     Noise,
+    Pulse {
+        duty: FreqType,
+    },
+    /// Noise losing 3dB per octave, softer than white noise like wind or rain
+    PinkNoise,
+    /// One period of a custom waveform, interpolated between the samples
+    Wavetable(Arc<[FreqType]>),
+    /// Positive half of a sine, silent for the other half period
+    HalfSine,
+    /// Rectified sine, sounds an octave higher with strong even harmonics
+    AbsSine,
+    /// Sum of detuned saws, `detune` is in cents between the center and the outermost voices.
+    /// About as loud as a single saw, but the peaks go above 1.0 when the voices line up
+    Supersaw {
+        voices: u8,
+        detune: FreqType,
+    },
 }
 
-pub fn osc(
-    dt: FreqType,
-    freq: FreqType,
-    wave: WaveType,
-    lfo_hertz: FreqType,
-    lfo_amplitude: FreqType,
-) -> FreqType {
-    let mut phase = w(freq) * dt;
-    let lfo_phase = w(lfo_hertz) * dt;
-    phase += lfo_amplitude * lfo_phase * lfo_phase.sin();
-    match wave {
-        WaveType::Sine => phase.sin(),
-        WaveType::Square => phase.sin().signum(),
-        WaveType::Triangle => phase.sin().asin() * FRAC_2_PI,
-        WaveType::SawSlow => {
+const SAW_SLOW_TABLE_SIZE: usize = 4096;
+
+/// One period of the 49 terms Fourier series of a saw wave, computed once
+static SAW_SLOW_TABLE: Lazy<Vec<FreqType>> = Lazy::new(|| {
+    (0..SAW_SLOW_TABLE_SIZE)
+        .map(|i| {
+            let phase = i as FreqType / SAW_SLOW_TABLE_SIZE as FreqType * TAU;
             let out = (1..50)
                 .map(|x| x as FreqType)
                 .fold(0.0, |acc, curr| acc + ((curr * phase).sin() / curr));
             out * FRAC_2_PI
+        })
+        .collect()
+});
+
+/// Linearly interpolates one period of a wave stored in `table` at the given phase
+fn wavetable(table: &[FreqType], phase: FreqType) -> FreqType {
+    if table.is_empty() {
+        return 0.0;
+    }
+    let position = phase.rem_euclid(TAU) / TAU * table.len() as FreqType;
+    let index = position as usize % table.len();
+    let next = (index + 1) % table.len();
+    let frac = position.fract();
+    table[index] * (1.0 - frac) + table[next] * frac
+}
+
+/// Rising saw band-limited with PolyBLEP, `increment` is in periods
+fn saw(phase: FreqType, increment: FreqType) -> FreqType {
+    let t = phase.rem_euclid(TAU) / TAU;
+    2.0 * t - 1.0 - poly_blep(t, increment)
+}
+
+/// PolyBLEP residual that smooths a unit step at `t == 0`, `t` and `increment` are in periods
+fn poly_blep(t: FreqType, increment: FreqType) -> FreqType {
+    if t < increment {
+        let t = t / increment;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - increment {
+        let t = (t - 1.0) / increment;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+impl WaveType {
+    /// Value of the wave at `phase` in radians, one period is `TAU`. `increment` is the phase
+    /// advance per sample in periods, used to band-limit the discontinuous waves, 0.0 gives
+    /// the naive wave
+    pub fn sample(&self, phase: FreqType, increment: FreqType) -> FreqType {
+        match self {
+            WaveType::Sine => phase.sin(),
+            WaveType::Square => {
+                let t = phase.rem_euclid(TAU) / TAU;
+                let naive = if t < 0.5 { 1.0 } else { -1.0 };
+                naive + poly_blep(t, increment) - poly_blep((t + 0.5).fract(), increment)
+            }
+            WaveType::Triangle => {
+                // Shifted by a quarter period so it starts at 0 and rises like a sine
+                let t = (phase.rem_euclid(TAU) / TAU + 0.25).fract();
+                1.0 - 4.0 * (t - 0.5).abs()
+            }
+            WaveType::SawSlow => wavetable(&SAW_SLOW_TABLE, phase),
+            WaveType::SawFast => saw(phase, increment),
+            // Stateless, so pink noise starts as white noise and is filtered by the note
+            WaveType::Noise | WaveType::PinkNoise => fastrand::f64() * 2.0 - 1.0,
+            WaveType::Wavetable(table) => wavetable(table, phase),
+            WaveType::HalfSine => phase.sin().max(0.0),
+            WaveType::AbsSine => phase.sin().abs(),
+            WaveType::Supersaw { voices, detune } => {
+                let voices = (*voices).max(1);
+                let sum = (0..voices)
+                    .map(|voice| {
+                        // Spread evenly from -detune to detune
+                        let spread = if voices == 1 {
+                            0.0
+                        } else {
+                            2.0 * voice as FreqType / (voices - 1) as FreqType - 1.0
+                        };
+                        let ratio = 2.0_f64.powf(detune * spread / 1200.0);
+                        // Starting the voices at different phases avoids a loud click at the start
+                        let start = TAU * (voice as FreqType * 0.618_034).fract();
+                        saw(phase * ratio + start, increment * ratio)
+                    })
+                    .sum::<FreqType>();
+                // The voices drift apart so they add up in power, like `normalize_mix`
+                sum / (voices as FreqType).sqrt()
+            }
+            WaveType::Pulse { duty } => {
+                if phase.rem_euclid(TAU) / TAU < duty.clamp(0.01, 0.99) {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
         }
-        WaveType::SawFast => (phase % TAU) / PI - 1.0,
-        WaveType::Noise => fastrand::i32(-1..1) as FreqType,
     }
 }
 
-#[derive(Clone, Copy)]
+/// `phase_offset` is in radians and can be modulated by another oscillator for FM synthesis
+pub fn osc(
+    dt: FreqType,
+    freq: FreqType,
+    wave: &WaveType,
+    phase_offset: FreqType,
+    sample_rate: u32,
+) -> FreqType {
+    // Phase advance per sample in periods, used to band-limit the discontinuous waves
+    let increment = (freq / sample_rate as FreqType).min(0.5);
+    wave.sample(w(freq) * dt + phase_offset, increment)
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum EnvelopeCurve {
+    Linear,
+    Exponential,
+}
+
+impl EnvelopeCurve {
+    /// Maps the linear progress of a stage (0 to 1) to the progress of the curve
+    fn shape(self, progress: FreqType) -> FreqType {
+        const K: FreqType = 5.0;
+        match self {
+            EnvelopeCurve::Linear => progress,
+            EnvelopeCurve::Exponential => (1.0 - (-K * progress).exp()) / (1.0 - (-K).exp()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EnvelopeADSR {
     pub attack_time: FreqType,
     pub decay_time: FreqType,
     pub sustain_amplitude: FreqType,
     pub release_time: FreqType,
     pub start_amplitude: FreqType,
+    pub curve: EnvelopeCurve,
 }
 
 impl Default for EnvelopeADSR {
@@ -88,12 +454,24 @@ impl Default for EnvelopeADSR {
             sustain_amplitude: 1.0,
             release_time: 0.2,
             start_amplitude: 1.0,
+            curve: EnvelopeCurve::Linear,
         }
     }
 }
 
 impl EnvelopeADSR {
     pub fn amplitude(&self, dt: FreqType, dt_on: FreqType, dt_off: FreqType) -> FreqType {
+        self.amplitude_from(dt, dt_on, dt_off, 0.0)
+    }
+
+    /// Like `amplitude` but the attack starts from `attack_from` instead of silence
+    pub fn amplitude_from(
+        &self,
+        dt: FreqType,
+        dt_on: FreqType,
+        dt_off: FreqType,
+        attack_from: FreqType,
+    ) -> FreqType {
         if dt_on <= 0.0 {
             return 0.0;
         }
@@ -106,10 +484,12 @@ impl EnvelopeADSR {
 
         let mut amplitude = if lifetime <= self.attack_time {
             // Attack
-            (lifetime / self.attack_time) * self.start_amplitude
+            self.curve.shape(lifetime / self.attack_time) * (self.start_amplitude - attack_from)
+                + attack_from
         } else if lifetime <= (self.attack_time + self.decay_time) {
             // Decay
-            ((lifetime - self.attack_time) / self.decay_time)
+            self.curve
+                .shape((lifetime - self.attack_time) / self.decay_time)
                 * (self.sustain_amplitude - self.start_amplitude)
                 + self.start_amplitude
         } else {
@@ -119,7 +499,8 @@ impl EnvelopeADSR {
 
         if dt_on <= dt_off {
             // Release
-            amplitude = ((dt - dt_off) / self.release_time) * -amplitude + amplitude;
+            amplitude =
+                self.curve.shape((dt - dt_off) / self.release_time) * -amplitude + amplitude;
         }
 
         if amplitude <= 0.0001 {
@@ -128,17 +509,117 @@ impl EnvelopeADSR {
 
         amplitude
     }
+
+    /// Amplitude at every sample from 0 to `end` seconds, for a note played at `on` and released at `off`
+    pub fn render(
+        &self,
+        on: FreqType,
+        off: FreqType,
+        end: FreqType,
+        sample_rate: u32,
+    ) -> Vec<FreqType> {
+        let num_samples = (end * sample_rate as FreqType) as usize;
+        (0..=num_samples)
+            .map(|sample| {
+                let dt = sample as FreqType / sample_rate as FreqType;
+                if dt < on {
+                    0.0
+                } else if dt < off {
+                    // Like a playing note, the release time is only known once released
+                    self.amplitude(dt, on, 0.0)
+                } else {
+                    self.amplitude(dt, on, off)
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the note was released long enough ago for the release to be over
+    pub fn is_finished(&self, dt: FreqType, dt_on: FreqType, dt_off: FreqType) -> bool {
+        dt_off > dt_on && dt - dt_off >= self.release_time
+    }
 }
 
+/// Plays the notes on the audio thread, which owns the data so it never waits for a lock.
+/// The other threads change it through the events and read it through the published status
 pub struct NoiseMaker {
-    pub data: Arc<Mutex<NoiseMakerData>>,
+    data: NoiseMakerData,
+    status: Arc<Mutex<NoiseMakerStatus>>,
+    /// Frames left before the status is published again
+    frames_until_status: usize,
     num_sample: usize,
+    sample_rate: u32,
     instruments: Vec<InstrumentType>,
+    events: Receiver<NoteEvent>,
+    /// Right sample of the current frame, waiting to be interleaved
+    pending_right: Option<f32>,
+    /// Output again while frozen
+    last_frame: (FreqType, FreqType),
 }
 
 pub struct NoiseMakerData {
     pub dt: FreqType,
     pub notes: Vec<Note>,
+    pub effects: Vec<EffectType>,
+    /// LFOs of the master bus, the pitch can only be modulated by the LFOs of the instruments
+    pub lfos: Vec<Lfo>,
+    /// The only gain of the output, the live outputs and the renders play at the same level.
+    /// Smoothed to avoid clicks when the volume is changed while playing
+    pub master_volume: SmoothedParam,
+    /// Maximum number of notes playing at once, the oldest note is dropped to make room
+    pub max_voices: usize,
+    pub sustain_pedal: bool,
+    /// Only one note plays at a time, gliding from the held note to the new one
+    pub mono: bool,
+    /// A releasing note played again continues its attack from its current amplitude instead of silence
+    pub legato: bool,
+    pub glide_time: FreqType,
+    pub tuning: TuningType,
+    /// Pitch bend of every note in semitones
+    pub pitch_bend: FreqType,
+    /// Vibrato of every note, added to the pitch LFOs of the instruments and restarted with every note
+    pub vibrato: Lfo,
+    /// Pans the notes by pitch like a piano, low notes left and high notes right.
+    /// 0.0 keeps them centered, at 1.0 the notes 4 octaves away from the center are fully panned
+    pub keyboard_pan_spread: FreqType,
+    /// Midi note left centered by `keyboard_pan_spread`
+    pub keyboard_pan_center: u8,
+    /// Repeats the last frame without advancing `dt`, to inspect the output while debugging
+    pub frozen: bool,
+    /// Always the last stage so the effects can't add an offset either
+    dc_blocker: DcBlocker,
+    /// Seeds the generator of every new note
+    rng: Rng,
+    /// Envelopes replacing the ones of the instruments, by instrument id
+    envelope_overrides: HashMap<usize, EnvelopeADSR>,
+    /// `Instrument::oscillator_voices` by instrument id, sizes the pink noise state of new notes
+    oscillator_voices: Vec<usize>,
+    /// Peak of the output, falling slowly so it can be displayed
+    peak: FreqType,
+    /// Gain of the mix of the notes, following the number of held voices, see `normalize_mix`
+    mix_gain: SmoothedParam,
+}
+
+/// Copy of the state of the audio thread, published regularly for the display
+#[derive(Default)]
+pub struct NoiseMakerStatus {
+    pub dt: FreqType,
+    pub active_voice_count: usize,
+    pub peak_amplitude: FreqType,
+    pub idle: bool,
+    pub notes: Vec<NoteStatus>,
+}
+
+/// Copy of a playing note, see `NoiseMakerStatus`
+#[derive(Clone, Copy, Debug)]
+pub struct NoteStatus {
+    pub id: u8,
+    pub instrument_id: usize,
+    pub on: FreqType,
+    pub active: bool,
+    pub freq: FreqType,
+    /// Current envelope amplitude
+    pub amplitude: FreqType,
 }
 
 impl Default for NoiseMakerData {
@@ -146,34 +627,457 @@ impl Default for NoiseMakerData {
         Self {
             dt: 0.0,
             notes: Vec::new(),
+            effects: Vec::new(),
+            lfos: Vec::new(),
+            master_volume: SmoothedParam::new(
+                0.2,
+                SmoothedParam::DEFAULT_TIME_SECS,
+                DEFAULT_SAMPLE_RATE,
+            ),
+            max_voices: 16,
+            sustain_pedal: false,
+            mono: false,
+            legato: false,
+            glide_time: 0.1,
+            frozen: false,
+            tuning: TuningType::from(EqualTemperament::default()),
+            pitch_bend: 0.0,
+            vibrato: Lfo::default(),
+            keyboard_pan_spread: 0.0,
+            keyboard_pan_center: 60,
+            dc_blocker: DcBlocker::new(),
+            rng: Rng::new(),
+            envelope_overrides: HashMap::new(),
+            oscillator_voices: Vec::new(),
+            peak: 0.0,
+            mix_gain: SmoothedParam::new(1.0, MIX_GAIN_SMOOTHING_SECS, DEFAULT_SAMPLE_RATE),
+        }
+    }
+}
+
+impl NoiseMakerData {
+    /// Registers the instruments the notes will be played with, so the notes get their state when
+    /// they start instead of on the audio thread
+    pub fn set_instruments(&mut self, instruments: &[InstrumentType]) {
+        self.oscillator_voices = instruments
+            .iter()
+            .map(|instrument| instrument.oscillator_voices())
+            .collect();
+    }
+
+    /// Takes the field so it can be called while a note is borrowed
+    fn new_pink_noise(oscillator_voices: &[usize], instrument_id: usize) -> Vec<PinkNoise> {
+        let voices = oscillator_voices.get(instrument_id).copied();
+        vec![PinkNoise::default(); voices.unwrap_or(0)]
+    }
+
+    /// Replaces the envelope of an instrument, including for the notes already playing
+    pub fn set_envelope(&mut self, instrument_id: usize, envelope: EnvelopeADSR) {
+        self.envelope_overrides.insert(instrument_id, envelope);
+        for note in self
+            .notes
+            .iter_mut()
+            .filter(|note| note.instrument_id == instrument_id)
+        {
+            note.envelope = Some(envelope);
+        }
+    }
+
+    /// Envelope set by `set_envelope` for an instrument
+    pub fn envelope_override(&self, instrument_id: usize) -> Option<EnvelopeADSR> {
+        self.envelope_overrides.get(&instrument_id).copied()
+    }
+
+    /// Number of notes playing or releasing, the notes count against `max_voices` until their
+    /// release is over
+    pub fn active_voice_count(&self) -> usize {
+        self.notes.iter().filter(|note| note.active).count()
+    }
+
+    /// Recent peak of the output after the effects, 1.0 is full scale
+    pub fn peak_amplitude(&self) -> FreqType {
+        self.peak
+    }
+
+    /// No note is playing and the tails of the effects have died out, so the output is silent
+    pub fn is_idle(&self) -> bool {
+        self.notes.is_empty() && self.peak < IDLE_PEAK
+    }
+
+    /// Copies the state shown by the display into `status`, reusing its memory
+    pub fn write_status(&self, status: &mut NoiseMakerStatus) {
+        status.dt = self.dt;
+        status.active_voice_count = self.active_voice_count();
+        status.peak_amplitude = self.peak;
+        status.idle = self.is_idle();
+        status.notes.clear();
+        status
+            .notes
+            .extend(self.notes.iter().map(|note| NoteStatus {
+                id: note.id,
+                instrument_id: note.instrument_id,
+                on: note.on,
+                active: note.active,
+                freq: note.freq(self.dt, &self.tuning),
+                amplitude: note.amplitude,
+            }));
+    }
+
+    /// Makes the noise of the following notes reproducible, the same seed and events give the same output
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Rng::with_seed(seed);
+    }
+
+    /// Advances to `dt` and mixes the next stereo frame, with the master volume and effects applied
+    pub fn next_frame(
+        &mut self,
+        dt: FreqType,
+        instruments: &[InstrumentType],
+        sample_rate: u32,
+    ) -> (FreqType, FreqType) {
+        self.dt = dt;
+        self.bend_notes(sample_rate);
+        let (left, right) = self.make_noise(instruments, sample_rate);
+        let volume = self.master_volume.next_sample()
+            * (1.0 + Lfo::sum(&self.lfos, LfoTarget::Amplitude, dt, sample_rate));
+        let pan = Lfo::sum(&self.lfos, LfoTarget::Pan, dt, sample_rate);
+        let (left, right) = if pan != 0.0 {
+            // Balance rather than pan, the notes are already stereo
+            let (left_gain, right_gain) = pan_gains(pan);
+            (left * left_gain * SQRT_2, right * right_gain * SQRT_2)
+        } else {
+            (left, right)
+        };
+        if self
+            .lfos
+            .iter()
+            .any(|lfo| lfo.target == LfoTarget::FilterCutoff)
+        {
+            let octaves = Lfo::sum(&self.lfos, LfoTarget::FilterCutoff, dt, sample_rate);
+            for effect in self.effects.iter_mut() {
+                effect.modulate_cutoff(octaves);
+            }
+        }
+        let (left, right) = self
+            .effects
+            .iter_mut()
+            .fold((left * volume, right * volume), |(left, right), effect| {
+                effect.process_stereo(left, right)
+            });
+        let (left, right) = self.dc_blocker.process_stereo(left, right);
+        let decay = (-1.0 / (PEAK_DECAY_SECS * sample_rate as FreqType)).exp();
+        self.peak = (self.peak * decay).max(left.abs()).max(right.abs());
+        (left, right)
+    }
+
+    /// Mixes the notes at `dt` and drops the notes whose release is over
+    fn make_noise(
+        &mut self,
+        instruments: &[InstrumentType],
+        sample_rate: u32,
+    ) -> (FreqType, FreqType) {
+        if self.notes.is_empty() {
+            return (0.0, 0.0);
+        }
+        let dt = self.dt;
+        let tuning = &self.tuning;
+        let keyboard_pan_spread = self.keyboard_pan_spread;
+        let keyboard_pan_center = self.keyboard_pan_center as FreqType;
+        let (left, right) = self
+            .notes
+            .iter_mut()
+            .map(|note| {
+                let (left, right, finished) =
+                    instruments[note.instrument_id].play_note_stereo(dt, note, tuning, sample_rate);
+                if finished && note.off > note.on {
+                    note.active = false;
+                }
+                if keyboard_pan_spread == 0.0 {
+                    return (left, right);
+                }
+                // Balance rather than pan, the notes are already stereo
+                let pan = keyboard_pan_spread * (note.id as FreqType - keyboard_pan_center)
+                    / KEYBOARD_PAN_RANGE;
+                let (left_gain, right_gain) = pan_gains(pan);
+                (left * left_gain * SQRT_2, right * right_gain * SQRT_2)
+            })
+            .fold((0.0, 0.0), |(acc_left, acc_right), (left, right)| {
+                (acc_left + left, acc_right + right)
+            });
+
+        // The releasing notes keep the gain they were held with, and the short hits don't make the
+        // other notes duck on every beat
+        let voice_count = self
+            .notes
+            .iter()
+            .filter(|note| note.off < note.on && !instruments[note.instrument_id].is_percussive())
+            .count();
+        if voice_count > 0 {
+            self.mix_gain.set_target(normalize_mix(1.0, voice_count));
+        }
+        self.notes.retain(|note| note.active);
+
+        let gain = self.mix_gain.next_sample();
+        (left * gain, right * gain)
+    }
+
+    /// Moves the pitch bend of the notes toward the global pitch bend, and integrates it with the
+    /// global vibrato
+    fn bend_notes(&mut self, sample_rate: u32) {
+        let dt = self.dt;
+        let smoothing = 1.0 - (-1.0 / (PITCH_BEND_SMOOTHING_SECS * sample_rate as FreqType)).exp();
+        for note in self.notes.iter_mut() {
+            if (self.pitch_bend - note.pitch_bend).abs() < 1e-6 {
+                note.pitch_bend = self.pitch_bend;
+            } else {
+                note.pitch_bend += (self.pitch_bend - note.pitch_bend) * smoothing;
+            }
+            let semitones = note.pitch_bend + self.vibrato.value(dt - note.on, sample_rate);
+            if semitones == 0.0 {
+                continue;
+            }
+            // Integrated so the wave stays continuous while bending
+            let extra_freq = note.freq(dt, &self.tuning) * (2.0_f64.powf(semitones / 12.0) - 1.0);
+            note.bend_phase += w(extra_freq) / sample_rate as FreqType;
+        }
+    }
+
+    /// Moves the held note of the instrument to `id` instead of playing a new note, returns false
+    /// if the instrument has no held note
+    fn glide_to(&mut self, id: u8, instrument_id: usize, velocity: FreqType) -> bool {
+        let dt = self.dt;
+        let glide_time = self.glide_time;
+        let tuning = &self.tuning;
+        let held = self
+            .notes
+            .iter_mut()
+            .filter(|note| note.active && note.off < note.on && note.instrument_id == instrument_id)
+            .last();
+        match held {
+            Some(note) => {
+                // Retriggering the same note doesn't glide
+                if note.id != id {
+                    note.glide = Some(Glide {
+                        from_freq: note.freq(dt, tuning),
+                        start: dt,
+                        start_phase: note.phase(dt, tuning),
+                        time: glide_time,
+                    });
+                    note.id = id;
+                    note.velocity = velocity;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn steal_oldest_voice(&mut self) {
+        let oldest = self
+            .notes
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.on.partial_cmp(&b.on).unwrap_or(Ordering::Equal))
+            .map(|(index, _)| index);
+        if let Some(index) = oldest {
+            self.notes.remove(index);
+        }
+    }
+
+    /// Starts playing `id`, or retriggers it if it is already playing
+    pub fn note_on(&mut self, id: u8, instrument_id: usize, velocity: FreqType) {
+        let dt = self.dt;
+        if self.mono && self.glide_to(id, instrument_id, velocity) {
+            return;
+        }
+        let legato = self.legato;
+        if let Some(note) = self.notes.iter_mut().find(|note| note.id == id) {
+            // The key is held again, so lifting the pedal shouldn't release it
+            note.sustained = false;
+            if note.off > note.on {
+                note.attack_from = if legato { note.amplitude } else { 0.0 };
+                note.on = dt;
+                note.active = true;
+                note.glide = None;
+                note.velocity = velocity;
+                note.off_velocity = DEFAULT_OFF_VELOCITY;
+                // Plucked again from the start
+                note.string = None;
+                if note.instrument_id != instrument_id {
+                    // The filter of the previous instrument may have another mode
+                    note.instrument_id = instrument_id;
+                    note.envelope = self.envelope_overrides.get(&instrument_id).copied();
+                    note.filter = None;
+                    note.pink_noise = Self::new_pink_noise(&self.oscillator_voices, instrument_id);
+                }
+            }
+        } else {
+            if self.active_voice_count() >= self.max_voices {
+                self.steal_oldest_voice();
+            }
+            self.notes.push(Note {
+                id,
+                on: dt,
+                off: 0.0,
+                instrument_id,
+                active: true,
+                velocity,
+                sustained: false,
+                glide: None,
+                pitch_bend: self.pitch_bend,
+                bend_phase: 0.0,
+                lfo_phase: 0.0,
+                envelope: self.envelope_overrides.get(&instrument_id).copied(),
+                off_velocity: DEFAULT_OFF_VELOCITY,
+                amplitude: 0.0,
+                attack_from: 0.0,
+                string: None,
+                rng: Rng::with_seed(self.rng.u64(..)),
+                pink_noise: Self::new_pink_noise(&self.oscillator_voices, instrument_id),
+                filter: None,
+            });
+        }
+    }
+
+    /// Releases `id`, or keeps it playing until the sustain pedal is lifted.
+    /// `velocity` is the release velocity, see `Note::off_velocity`
+    pub fn note_off(&mut self, id: u8, velocity: FreqType) {
+        let dt = self.dt;
+        let sustain_pedal = self.sustain_pedal;
+        if let Some(note) = self.notes.iter_mut().find(|note| note.id == id) {
+            if note.off < note.on {
+                note.off_velocity = velocity;
+                if sustain_pedal {
+                    note.sustained = true;
+                } else {
+                    note.off = dt;
+                }
+            }
+        }
+    }
+
+    pub fn apply_event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::On {
+                id,
+                instrument_id,
+                velocity,
+            } => self.note_on(id, instrument_id, velocity),
+            NoteEvent::Off { id, velocity } => self.note_off(id, velocity),
+            NoteEvent::PitchBend { semitones } => self.pitch_bend = semitones,
+            NoteEvent::SustainPedal { down } => {
+                let dt = self.dt;
+                self.sustain_pedal = down;
+                if !down {
+                    for note in self.notes.iter_mut().filter(|note| note.sustained) {
+                        note.sustained = false;
+                        note.off = dt;
+                    }
+                }
+            }
+            NoteEvent::MasterVolume { volume } => self.master_volume.set_target(volume),
+            NoteEvent::Vibrato { depth, rate } => {
+                self.vibrato.depth = depth;
+                self.vibrato.rate = rate;
+            }
+            NoteEvent::Mono { enabled } => self.mono = enabled,
+            NoteEvent::Legato { enabled } => self.legato = enabled,
+            NoteEvent::Envelope {
+                instrument_id,
+                envelope,
+            } => self.set_envelope(instrument_id, envelope),
+            NoteEvent::Freeze { frozen } => self.frozen = frozen,
+            NoteEvent::Cutoff { cutoff } => {
+                for effect in self.effects.iter_mut() {
+                    effect.set_cutoff(cutoff);
+                }
+            }
         }
     }
 }
 
+/// Locks the status even if a thread panicked while holding it, it is only a copy so it can
+/// still be trusted
+pub fn lock_status(status: &Mutex<NoiseMakerStatus>) -> MutexGuard<'_, NoiseMakerStatus> {
+    status
+        .lock()
+        .unwrap_or_else(|poisoned| recover_status(status, poisoned))
+}
+
+/// Like `lock_status` but returns `None` instead of waiting when the status is already locked
+fn try_lock_status(status: &Mutex<NoiseMakerStatus>) -> Option<MutexGuard<'_, NoiseMakerStatus>> {
+    match status.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(recover_status(status, poisoned)),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+fn recover_status<'a>(
+    status: &Mutex<NoiseMakerStatus>,
+    poisoned: PoisonError<MutexGuard<'a, NoiseMakerStatus>>,
+) -> MutexGuard<'a, NoiseMakerStatus> {
+    log::error!("A thread panicked while holding the noise maker status");
+    status.clear_poison();
+    poisoned.into_inner()
+}
+
 impl NoiseMaker {
-    pub fn new(data: Arc<Mutex<NoiseMakerData>>, instruments: Vec<InstrumentType>) -> Self {
+    /// Frames between two updates of the status, about 5ms
+    const STATUS_INTERVAL: usize = 256;
+
+    pub fn new(
+        mut data: NoiseMakerData,
+        instruments: Vec<InstrumentType>,
+        events: Receiver<NoteEvent>,
+        status: Arc<Mutex<NoiseMakerStatus>>,
+        sample_rate: u32,
+    ) -> Self {
+        // Resume from the time of the data so the notes already playing keep their timing
+        let num_sample = (data.dt * sample_rate as FreqType) as usize;
+        data.set_instruments(&instruments);
         Self {
             data,
-            num_sample: 0,
+            status,
+            frames_until_status: 0,
+            num_sample,
+            sample_rate,
             instruments,
+            events,
+            pending_right: None,
+            last_frame: (0.0, 0.0),
+        }
+    }
+
+    /// Publishes the status every `STATUS_INTERVAL` frames, or at the next frame if the status is
+    /// being read
+    fn publish_status(&mut self) {
+        if self.frames_until_status > 0 {
+            self.frames_until_status -= 1;
+            return;
+        }
+        if let Some(mut status) = try_lock_status(&self.status) {
+            self.data.write_status(&mut status);
+            self.frames_until_status = Self::STATUS_INTERVAL;
         }
     }
 }
 
+#[cfg(feature = "audio")]
 impl Source for NoiseMaker {
     fn current_frame_len(&self) -> Option<usize> {
         None
     }
 
     fn channels(&self) -> u16 {
-        1
+        2
     }
 
     fn sample_rate(&self) -> u32 {
-        48000
+        self.sample_rate
     }
 
-    fn total_duration(&self) -> Option<Duration> {
+    fn total_duration(&self) -> Option<std::time::Duration> {
         None
     }
 }
@@ -183,33 +1087,536 @@ impl Iterator for NoiseMaker {
 
     #[inline]
     fn next(&mut self) -> Option<f32> {
-        let noise = if let Ok(mut data) = self.data.lock() {
+        if let Some(right) = self.pending_right.take() {
+            return Some(right);
+        }
+
+        if !self.data.frozen {
             self.num_sample = self.num_sample.wrapping_add(1);
-            data.dt = self.num_sample as FreqType / self.sample_rate() as FreqType;
-            make_noise(data.dt, &mut data.notes, &self.instruments)
+            self.data.dt = self.num_sample as FreqType / self.sample_rate as FreqType;
+        }
+        for event in self.events.try_iter() {
+            self.data.apply_event(event);
+            // The main thread pauses the output while the status is idle
+            self.frames_until_status = 0;
+        }
+        // Time stands still while frozen, the events change the notes at the frozen time
+        let (left, right) = if self.data.frozen {
+            self.last_frame
         } else {
-            0.0
+            let dt = self.data.dt;
+            self.data
+                .next_frame(dt, &self.instruments, self.sample_rate)
         };
-        // dbg!(noise);
-        Some(noise as f32)
+        self.publish_status();
+        self.last_frame = (left, right);
+        self.pending_right = Some(right as f32);
+        Some(left as f32)
+    }
+}
+
+/// Renders interleaved stereo samples without an audio device.
+/// `events` are applied at their time in seconds, and must be sorted by time.
+/// The output is the same every time with a `seed`.
+pub fn render(
+    instruments: Vec<InstrumentType>,
+    events: &[(FreqType, NoteEvent)],
+    duration_secs: FreqType,
+    seed: Option<u64>,
+    sample_rate: u32,
+) -> Vec<f32> {
+    let mut data = NoiseMakerData::default();
+    data.set_instruments(&instruments);
+    if let Some(seed) = seed {
+        data.seed(seed);
+    }
+    let mut events = events.iter().peekable();
+    let num_frames = (duration_secs * sample_rate as FreqType) as usize;
+    let mut samples = Vec::with_capacity(num_frames * 2);
+    for num_sample in 1..=num_frames {
+        let dt = num_sample as FreqType / sample_rate as FreqType;
+        data.dt = dt;
+        while let Some((_, event)) = events.next_if(|(time, _)| *time <= dt) {
+            data.apply_event(*event);
+        }
+        let (left, right) = data.next_frame(dt, &instruments, sample_rate);
+        samples.push(left as f32);
+        samples.push(right as f32);
     }
+    samples
+}
+
+/// Scales the sum of the voices by the square root of their count,
+/// uncorrelated voices add up in power so a chord stays about as loud as a single note
+pub fn normalize_mix(sum: FreqType, voice_count: usize) -> FreqType {
+    sum / (voice_count.max(1) as FreqType).sqrt()
 }
 
-fn make_noise(dt: FreqType, notes: &mut Vec<Note>, instruments: &[InstrumentType]) -> FreqType {
-    let mixed_output: FreqType = notes
-        .iter_mut()
-        .map(|note| {
-            let (sound, finished) = instruments[note.instrument_id].play_note(dt, note);
-            if finished && note.off > note.on {
-                note.active = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// Samples one period of `wave` at `freq` through `osc`, without any LFO
+    fn period(wave: &WaveType, freq: FreqType) -> Vec<FreqType> {
+        let samples = (SAMPLE_RATE as FreqType / freq) as usize;
+        (0..samples)
+            .map(|i| {
+                let dt = i as FreqType / SAMPLE_RATE as FreqType;
+                osc(dt, freq, wave, 0.0, SAMPLE_RATE)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_starts_at_zero_and_peaks_at_a_quarter_period() {
+        let freq = 100.0;
+        assert!(osc(0.0, freq, &WaveType::Sine, 0.0, SAMPLE_RATE).abs() < 1e-9);
+        let quarter = 0.25 / freq;
+        assert!((osc(quarter, freq, &WaveType::Sine, 0.0, SAMPLE_RATE) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn square_is_plus_or_minus_one() {
+        for i in 0..1000 {
+            let value = WaveType::Square.sample(TAU * i as FreqType / 1000.0, 0.0);
+            assert!(value == 1.0 || value == -1.0, "{}", value);
+        }
+        // Band-limited, only the samples next to the edges are smoothed
+        let samples = period(&WaveType::Square, 100.0);
+        let exact = samples
+            .iter()
+            .filter(|value| (value.abs() - 1.0).abs() < 1e-9)
+            .count();
+        assert!(exact >= samples.len() - 4);
+    }
+
+    #[test]
+    fn triangle_stays_in_range_and_starts_at_zero() {
+        assert!(WaveType::Triangle.sample(0.0, 0.0).abs() < 1e-9);
+        for value in period(&WaveType::Triangle, 100.0) {
+            assert!((-1.0..=1.0).contains(&value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn saw_fast_ramps_from_minus_one_to_one() {
+        let samples = period(&WaveType::SawFast, 100.0);
+        let middle = &samples[2..samples.len() - 2];
+        assert!(middle.windows(2).all(|pair| pair[1] > pair[0]));
+        assert!((middle[0] + 1.0).abs() < 0.02);
+        assert!((middle[middle.len() - 1] - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn a_chord_is_not_louder_than_a_single_note_in_power() {
+        assert_eq!(normalize_mix(0.5, 1), 0.5);
+        assert!((normalize_mix(7.0, 7) - 7.0_f64.sqrt()).abs() < 1e-9);
+        assert_eq!(normalize_mix(0.0, 0), 0.0);
+    }
+
+    #[test]
+    fn only_held_melodic_notes_lower_the_mix_gain() {
+        let instruments = vec![
+            InstrumentType::builtin().remove(0),
+            InstrumentType::from(crate::instruments::Click::new()),
+        ];
+        let mut data = NoiseMakerData {
+            dt: 0.001,
+            ..NoiseMakerData::default()
+        };
+        data.note_on(60, 0, 1.0);
+        data.note_on(72, 1, 1.0);
+        data.next_frame(0.002, &instruments, SAMPLE_RATE);
+        assert_eq!(data.mix_gain.target(), 1.0);
+        data.note_on(64, 0, 1.0);
+        data.next_frame(0.003, &instruments, SAMPLE_RATE);
+        assert!((data.mix_gain.target() - FRAC_1_SQRT_2).abs() < 1e-9);
+        data.note_off(64, DEFAULT_OFF_VELOCITY);
+        data.next_frame(0.004, &instruments, SAMPLE_RATE);
+        assert_eq!(data.mix_gain.target(), 1.0);
+    }
+
+    #[test]
+    fn mono_glides_only_within_the_same_instrument() {
+        let mut data = NoiseMakerData {
+            dt: 0.001,
+            mono: true,
+            ..NoiseMakerData::default()
+        };
+        data.note_on(60, 0, 1.0);
+        data.note_on(36, 4, 1.0);
+        assert_eq!(data.notes.len(), 2);
+        assert_eq!(data.notes[0].id, 60);
+        assert!(data.notes[0].glide.is_none());
+        data.note_on(64, 0, 1.0);
+        assert_eq!(data.notes.len(), 2);
+        assert_eq!(data.notes[0].id, 64);
+        assert!(data.notes[0].glide.is_some());
+    }
+
+    #[test]
+    fn retriggering_a_releasing_note_restarts_it() {
+        let instruments = InstrumentType::builtin();
+        let plucked_string = instruments
+            .iter()
+            .position(|instrument| instrument.name() == "PluckedString")
+            .unwrap();
+        let mut data = NoiseMakerData {
+            dt: 0.001,
+            ..NoiseMakerData::default()
+        };
+        data.note_on(60, plucked_string, 1.0);
+        data.next_frame(0.002, &instruments, SAMPLE_RATE);
+        assert!(data.notes[0].string.is_some());
+        data.note_off(60, DEFAULT_OFF_VELOCITY);
+        data.next_frame(0.003, &instruments, SAMPLE_RATE);
+        data.note_on(60, plucked_string, 0.3);
+        assert!(data.notes[0].string.is_none());
+        assert_eq!(data.notes[0].velocity, 0.3);
+        assert_eq!(data.notes[0].on, data.dt);
+        data.next_frame(0.004, &instruments, SAMPLE_RATE);
+        data.note_off(60, DEFAULT_OFF_VELOCITY);
+        data.next_frame(0.005, &instruments, SAMPLE_RATE);
+        data.note_on(60, 0, 1.0);
+        assert_eq!(data.notes[0].instrument_id, 0);
+    }
+
+    /// Renders `envelope` at 1kHz, so an index is a millisecond, for a note held from 0.1s to 0.6s
+    fn render_envelope(envelope: EnvelopeADSR) -> Vec<FreqType> {
+        envelope.render(0.1, 0.6, 1.0, 1000)
+    }
+
+    fn test_envelope() -> EnvelopeADSR {
+        EnvelopeADSR {
+            attack_time: 0.1,
+            decay_time: 0.1,
+            sustain_amplitude: 0.5,
+            release_time: 0.2,
+            start_amplitude: 1.0,
+            curve: EnvelopeCurve::Linear,
+        }
+    }
+
+    #[test]
+    fn envelope_attack_reaches_the_start_amplitude_at_the_attack_time() {
+        for curve in [EnvelopeCurve::Linear, EnvelopeCurve::Exponential] {
+            let curve = render_envelope(EnvelopeADSR {
+                curve,
+                ..test_envelope()
+            });
+            assert_eq!(curve[100], 0.0);
+            assert!((curve[200] - 1.0).abs() < 1e-9, "{}", curve[200]);
+            assert!(curve[100..200].windows(2).all(|pair| pair[1] > pair[0]));
+            assert!(curve.iter().all(|amplitude| *amplitude <= 1.0 + 1e-9));
+        }
+    }
+
+    #[test]
+    fn envelope_sustain_holds_until_released() {
+        let curve = render_envelope(test_envelope());
+        assert!(curve[300..=600]
+            .iter()
+            .all(|amplitude| (amplitude - 0.5).abs() < 1e-9));
+    }
+
+    #[test]
+    fn envelope_release_decays_to_zero() {
+        for curve in [EnvelopeCurve::Linear, EnvelopeCurve::Exponential] {
+            let curve = render_envelope(EnvelopeADSR {
+                curve,
+                ..test_envelope()
+            });
+            assert!(curve[600..=800].windows(2).all(|pair| pair[1] <= pair[0]));
+            assert!(curve[800..].iter().all(|amplitude| *amplitude == 0.0));
+        }
+    }
+
+    #[test]
+    fn noise_is_centered_and_covers_both_signs() {
+        let samples = (0..10_000)
+            .map(|_| WaveType::Noise.sample(0.0, 0.0))
+            .collect::<Vec<_>>();
+        let mean = samples.iter().sum::<FreqType>() / samples.len() as FreqType;
+        assert!(mean.abs() < 0.05, "{}", mean);
+        assert!(samples.iter().any(|sample| *sample > 0.9));
+        assert!(samples.iter().any(|sample| *sample < -0.9));
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+    }
+
+    #[test]
+    fn exponential_attack_rises_faster_than_linear() {
+        let midpoint = |curve| {
+            let envelope = EnvelopeADSR {
+                curve,
+                ..test_envelope()
+            };
+            // Held from 0.1s, halfway through the 0.1s attack
+            envelope.amplitude(0.15, 0.1, 0.0)
+        };
+        let linear = midpoint(EnvelopeCurve::Linear);
+        let exponential = midpoint(EnvelopeCurve::Exponential);
+        assert!((linear - 0.5).abs() < 1e-9, "{}", linear);
+        assert!(exponential > 0.9 && exponential < 1.0, "{}", exponential);
+    }
+
+    #[test]
+    fn the_voice_over_the_limit_steals_the_oldest_note() {
+        let mut data = NoiseMakerData {
+            max_voices: 16,
+            ..NoiseMakerData::default()
+        };
+        for id in 0..16 {
+            data.dt = 0.001 * (id + 1) as FreqType;
+            data.note_on(40 + id, 0, 1.0);
+        }
+        assert_eq!(data.notes.len(), 16);
+        data.dt = 0.1;
+        data.note_on(80, 0, 1.0);
+        assert_eq!(data.notes.len(), 16);
+        assert!(data.notes.iter().all(|note| note.id != 40));
+        assert!(data.notes.iter().any(|note| note.id == 80));
+    }
+
+    #[test]
+    fn triangle_peaks_at_one_and_is_linear_in_between() {
+        let triangle = |period: FreqType| WaveType::Triangle.sample(TAU * period, 0.0);
+        assert!((triangle(0.25) - 1.0).abs() < 1e-12);
+        assert!((triangle(0.75) + 1.0).abs() < 1e-12);
+        // Falling by the same step at every point between the peaks
+        let steps = (0..=100)
+            .map(|i| triangle(0.25 + 0.5 * i as FreqType / 100.0))
+            .collect::<Vec<_>>();
+        for pair in steps.windows(2) {
+            assert!((pair[0] - pair[1] - 0.02).abs() < 1e-9, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn a_note_lasts_as_long_at_any_sample_rate() {
+        let events = [
+            (
+                0.0,
+                NoteEvent::On {
+                    id: 69,
+                    instrument_id: 0,
+                    velocity: 1.0,
+                },
+            ),
+            (
+                0.5,
+                NoteEvent::Off {
+                    id: 69,
+                    velocity: DEFAULT_OFF_VELOCITY,
+                },
+            ),
+        ];
+        let last_sound = |sample_rate: u32| {
+            let samples = render(
+                InstrumentType::builtin(),
+                &events,
+                2.0,
+                Some(1),
+                sample_rate,
+            );
+            assert_eq!(samples.len(), 2 * 2 * sample_rate as usize);
+            let last = samples
+                .iter()
+                .rposition(|sample| sample.abs() > 1e-3)
+                .unwrap();
+            (last / 2) as FreqType / sample_rate as FreqType
+        };
+        let at_44100 = last_sound(44100);
+        let at_48000 = last_sound(48000);
+        assert!(at_44100 > 0.5);
+        assert!(
+            (at_44100 - at_48000).abs() < 0.005,
+            "{} {}",
+            at_44100,
+            at_48000
+        );
+    }
+
+    #[test]
+    fn finished_notes_are_removed_and_the_others_keep_their_order() {
+        let instruments = InstrumentType::builtin();
+        let mut data = NoiseMakerData {
+            dt: 0.001,
+            ..NoiseMakerData::default()
+        };
+        for id in [60, 62, 64, 65, 67] {
+            data.note_on(id, 0, 1.0);
+        }
+        data.dt = 0.002;
+        data.note_off(62, DEFAULT_OFF_VELOCITY);
+        data.note_off(65, DEFAULT_OFF_VELOCITY);
+        // After the 0.2s release of default
+        data.next_frame(0.3, &instruments, SAMPLE_RATE);
+        let ids = data.notes.iter().map(|note| note.id).collect::<Vec<_>>();
+        assert_eq!(ids, [60, 64, 67]);
+    }
+
+    #[test]
+    fn note_on_and_off_set_the_times_of_the_note() {
+        let mut data = NoiseMakerData {
+            dt: 0.5,
+            ..NoiseMakerData::default()
+        };
+        data.note_on(60, 2, 0.8);
+        let note = &data.notes[0];
+        assert_eq!((note.id, note.instrument_id, note.velocity), (60, 2, 0.8));
+        assert_eq!((note.on, note.off), (0.5, 0.0));
+        assert!(note.active);
+
+        data.dt = 1.25;
+        data.note_off(60, 0.3);
+        let note = &data.notes[0];
+        assert_eq!((note.on, note.off), (0.5, 1.25));
+        assert_eq!(note.off_velocity, 0.3);
+        // Released notes stay until their release is over
+        assert!(note.active);
+        // Only the first release counts
+        data.dt = 1.5;
+        data.note_off(60, DEFAULT_OFF_VELOCITY);
+        assert_eq!(data.notes[0].off, 1.25);
+    }
+
+    #[test]
+    fn sine_wavetable_reproduces_a_sine() {
+        let size = 256;
+        let table = (0..size)
+            .map(|i| (TAU * i as FreqType / size as FreqType).sin())
+            .collect::<Arc<[FreqType]>>();
+        let wave = WaveType::Wavetable(table);
+        // Between the samples the error of the linear interpolation is below (TAU / size)^2 / 8
+        for i in 0..10_000 {
+            let phase = TAU * i as FreqType / 1234.5;
+            assert!(
+                (wave.sample(phase, 0.0) - phase.sin()).abs() < 1e-4,
+                "{}",
+                phase
+            );
+        }
+    }
+
+    #[test]
+    fn rendering_with_the_same_seed_gives_the_same_output() {
+        let noise: crate::instruments::CustomInstrument =
+            toml::from_str(r#"oscillators = [{ wave = "Noise" }, { wave = "PinkNoise" }]"#)
+                .unwrap();
+        let events = [(
+            0.0,
+            NoteEvent::On {
+                id: 69,
+                instrument_id: 0,
+                velocity: 1.0,
+            },
+        )];
+        let render_seeded = |seed: u64| {
+            render(
+                vec![InstrumentType::from(noise.clone())],
+                &events,
+                0.2,
+                Some(seed),
+                SAMPLE_RATE,
+            )
+        };
+        let first = render_seeded(7);
+        assert!(first.iter().any(|sample| sample.abs() > 0.01));
+        assert_eq!(first, render_seeded(7));
+        assert_ne!(first, render_seeded(8));
+    }
+
+    #[test]
+    fn rectified_sines_are_never_negative() {
+        for i in 0..1000 {
+            let phase = TAU * i as FreqType / 1000.0;
+            let half = WaveType::HalfSine.sample(phase, 0.0);
+            let abs = WaveType::AbsSine.sample(phase, 0.0);
+            assert!(half >= 0.0, "{}", half);
+            assert_eq!(abs, abs.abs());
+            // Both follow the sine over its positive half
+            if phase <= PI {
+                assert_eq!(half, phase.sin());
+                assert_eq!(abs, phase.sin());
+            } else {
+                assert_eq!(half, 0.0);
             }
-            sound
-        })
-        .sum();
+        }
+    }
 
-    while let Some(index) = notes.iter().position(|x| !x.active) {
-        notes.remove(index);
+    #[test]
+    fn keyboard_pan_spread_pans_low_notes_left_and_high_notes_right() {
+        let instruments = InstrumentType::builtin();
+        // Power of the left and right channels while `id` is held
+        let channels = |id: u8| {
+            let mut data = NoiseMakerData {
+                dt: 0.001,
+                keyboard_pan_spread: 1.0,
+                ..NoiseMakerData::default()
+            };
+            data.note_on(id, 0, 1.0);
+            (1..SAMPLE_RATE / 5).fold((0.0, 0.0), |(left, right), frame| {
+                let dt = 0.001 + frame as FreqType / SAMPLE_RATE as FreqType;
+                let (l, r) = data.next_frame(dt, &instruments, SAMPLE_RATE);
+                (left + l * l, right + r * r)
+            })
+        };
+        let (left, right) = channels(36);
+        assert!(left > 2.0 * right, "{} {}", left, right);
+        let (left, right) = channels(84);
+        assert!(right > 2.0 * left, "{} {}", left, right);
+        let (left, right) = channels(60);
+        assert!((left / right - 1.0).abs() < 1e-6, "{} {}", left, right);
     }
 
-    mixed_output * 0.2
+    #[test]
+    fn supersaw_voices_beat_against_each_other() {
+        // Lowest and highest RMS of 100ms windows over 2s of a 100Hz supersaw, relative to the mean
+        let rms_spread = |voices: u8| {
+            let wave = WaveType::Supersaw {
+                voices,
+                detune: 20.0,
+            };
+            let window = SAMPLE_RATE as usize / 10;
+            let rms = (0..20)
+                .map(|index| {
+                    let power = (index * window..(index + 1) * window)
+                        .map(|i| {
+                            let dt = i as FreqType / SAMPLE_RATE as FreqType;
+                            osc(dt, 100.0, &wave, 0.0, SAMPLE_RATE).powi(2)
+                        })
+                        .sum::<FreqType>();
+                    (power / window as FreqType).sqrt()
+                })
+                .collect::<Vec<_>>();
+            let mean = rms.iter().sum::<FreqType>() / rms.len() as FreqType;
+            let (min, max) = rms.iter().fold(
+                (FreqType::MAX, 0.0),
+                |(min, max): (FreqType, FreqType), rms| (min.min(*rms), max.max(*rms)),
+            );
+            (max - min) / mean
+        };
+        let single = rms_spread(1);
+        let thick = rms_spread(7);
+        assert!(single < 0.01, "{}", single);
+        assert!(thick > 0.5, "{}", thick);
+    }
+
+    #[test]
+    fn cutoff_event_moves_the_master_filters() {
+        let mut data = NoiseMakerData::default();
+        data.effects
+            .push(EffectType::from(crate::effects::LowPassFilter::new(
+                200.0,
+                SAMPLE_RATE,
+            )));
+        data.apply_event(NoteEvent::Cutoff { cutoff: 1500.0 });
+        match &data.effects[0] {
+            EffectType::LowPassFilter(filter) => assert_eq!(filter.cutoff(), 1500.0),
+            _ => unreachable!(),
+        }
+    }
 }
@@ -1,6 +1,18 @@
 use crate::noise_maker::FreqType;
 use derive_more::Display;
-use std::fmt;
+use once_cell::sync::Lazy;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+const A4_HZ: FreqType = 440.0;
+
+/// Frequencies of the midi notes with standard tuning, avoids a `powf` for every sample
+static FREQ_TABLE: Lazy<[FreqType; 128]> = Lazy::new(|| {
+    let mut table = [0.0; 128];
+    for (id, freq) in table.iter_mut().enumerate() {
+        *freq = Note::from(id as u8).freq_with_reference(A4_HZ);
+    }
+    table
+});
 
 #[derive(Clone, Copy, Display)]
 pub enum NoteLetter {
@@ -12,8 +24,28 @@ pub enum NoteLetter {
     A = 9,
     B = 11,
 }
+impl NoteLetter {
+    /// Every letter in order, from C to B
+    pub fn all() -> [NoteLetter; 7] {
+        use NoteLetter::*;
+        [C, D, E, F, G, A, B]
+    }
+
+    /// Semitones above C
+    pub fn to_semitone(self) -> u8 {
+        self as u8
+    }
+
+    /// Letter at `semitone` above C, `None` for the black keys
+    pub fn from_semitone(semitone: u8) -> Option<NoteLetter> {
+        Self::all()
+            .iter()
+            .copied()
+            .find(|letter| letter.to_semitone() == semitone)
+    }
+}
+
 #[derive(Clone, Copy, Display)]
-#[allow(dead_code)]
 pub enum Accidental {
     #[display(fmt = "b")]
     Flat = -1,
@@ -39,36 +71,67 @@ impl Note {
         }
     }
 
+    /// Midi note number, accidentals can cross into the neighbouring octave, Cb4 is B3 and B#3
+    /// is C4. The notes below C-1 or above G9, like Cb-1, are clamped to the midi range
     pub fn into_u8(self) -> u8 {
-        ((self.letter as i8 + self.accidental as i8) % 12) as u8 + self.octave * 12
+        self.semitones().clamp(0, 127) as u8
+    }
+
+    /// Like `into_u8`, but fails for the notes outside of the midi range instead of clamping them
+    pub fn checked_into_u8(self) -> Result<u8, NoteOutOfRange> {
+        u8::try_from(self.semitones())
+            .ok()
+            .filter(|id| *id <= 127)
+            .ok_or(NoteOutOfRange)
+    }
+
+    /// Semitones above C-1, can be out of the midi range
+    fn semitones(self) -> i16 {
+        self.octave as i16 * 12 + self.letter as i16 + self.accidental as i16
+    }
+
+    /// Moves the note by `semitones`, staying within the midi range of 0 to 127
+    pub fn transpose(self, semitones: i8) -> Note {
+        let id = (self.semitones() + semitones as i16).clamp(0, 127);
+        Note::from(id as u8)
     }
 
-    /// https://en.wikipedia.org/wiki/Musical_note#Note_frequency_(hertz)
     pub fn freq(self) -> FreqType {
-        2.0_f64.powf((self.into_u8() as FreqType - 69.0) / 12.0) * 440.0
+        match self.checked_into_u8() {
+            Ok(id) => FREQ_TABLE[id as usize],
+            Err(NoteOutOfRange) => self.freq_with_reference(A4_HZ),
+        }
+    }
+
+    /// https://en.wikipedia.org/wiki/Musical_note#Note_frequency_(hertz)
+    pub fn freq_with_reference(self, a4_hz: FreqType) -> FreqType {
+        2.0_f64.powf((self.semitones() as FreqType - 69.0) / 12.0) * a4_hz
     }
 }
 
 impl From<u8> for Note {
     fn from(val: u8) -> Self {
-        use Accidental::*;
-        use NoteLetter::*;
-        let octave = (val / 12) as u8;
-        match val % 12 {
-            0 => Note::new(C, None, octave),
-            1 => Note::new(C, Sharp, octave),
-            2 => Note::new(D, None, octave),
-            3 => Note::new(D, Sharp, octave),
-            4 => Note::new(E, None, octave),
-            5 => Note::new(F, None, octave),
-            6 => Note::new(F, Sharp, octave),
-            7 => Note::new(G, None, octave),
-            8 => Note::new(G, Sharp, octave),
-            9 => Note::new(A, None, octave),
-            10 => Note::new(A, Sharp, octave),
-            11 => Note::new(B, None, octave),
-            _ => unreachable!(),
-        }
+        let octave = val / 12;
+        let semitone = val % 12;
+        // Black keys are written as the sharp of the white key below
+        let letter = NoteLetter::all()
+            .iter()
+            .rev()
+            .copied()
+            .find(|letter| letter.to_semitone() <= semitone)
+            .unwrap_or(NoteLetter::C);
+        let accidental = if letter.to_semitone() == semitone {
+            Accidental::None
+        } else {
+            Accidental::Sharp
+        };
+        Note::new(letter, accidental, octave)
+    }
+}
+
+impl From<Note> for u8 {
+    fn from(note: Note) -> Self {
+        note.into_u8()
     }
 }
 
@@ -83,3 +146,197 @@ impl fmt::Display for Note {
         )
     }
 }
+
+/// Scales used to snap notes, see `quantize_to_scale`
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Scale {
+    Major,
+    /// Natural minor
+    Minor,
+    /// Major pentatonic
+    Pentatonic,
+}
+
+impl Scale {
+    /// Semitones of the degrees above the tonic
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Pentatonic => &[0, 2, 4, 7, 9],
+        }
+    }
+
+    /// Whether the midi note `id` is in the scale in the key of `tonic`
+    pub fn contains(self, id: u8, tonic: u8) -> bool {
+        let degree = (id as i16 - tonic as i16).rem_euclid(12) as u8;
+        self.intervals().contains(&degree)
+    }
+}
+
+/// Snaps the midi note `id` to the nearest note of `scale` in the key of `tonic`,
+/// only the pitch class of `tonic` matters. A note halfway between two degrees goes down.
+pub fn quantize_to_scale(id: u8, scale: Scale, tonic: u8) -> u8 {
+    let id = id.min(127);
+    (0..12)
+        .flat_map(|distance| [id.checked_sub(distance), id.checked_add(distance)])
+        .flatten()
+        .find(|candidate| *candidate <= 127 && scale.contains(*candidate, tonic))
+        .unwrap_or(id)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum ChordType {
+    Major,
+    Minor,
+    #[display(fmt = "Diminished")]
+    Diminished,
+    /// Major triad with a minor seventh
+    #[display(fmt = "Dominant 7th")]
+    Dominant7,
+}
+
+impl ChordType {
+    /// Semitones of the chord notes above the root
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            ChordType::Major => &[0, 4, 7],
+            ChordType::Minor => &[0, 3, 7],
+            ChordType::Diminished => &[0, 3, 6],
+            ChordType::Dominant7 => &[0, 4, 7, 10],
+        }
+    }
+}
+
+/// Midi notes of the chord built on `root_id`, the notes above 127 are left out
+pub fn chord_from_root(root_id: u8, chord: ChordType) -> Vec<u8> {
+    chord
+        .intervals()
+        .iter()
+        .map(|interval| root_id as u16 + *interval as u16)
+        .filter(|id| *id <= 127)
+        .map(|id| id as u8)
+        .collect()
+}
+
+#[derive(Debug, Display)]
+pub enum ParseNoteError {
+    #[display(fmt = "invalid note letter")]
+    InvalidLetter,
+    #[display(fmt = "invalid octave")]
+    InvalidOctave,
+    #[display(fmt = "{}", _0)]
+    OutOfRange(NoteOutOfRange),
+}
+
+impl std::error::Error for ParseNoteError {}
+
+/// The note is below C-1 or above G9, it has no midi note number
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+#[display(fmt = "note outside of the midi range, from C-1 to G9")]
+pub struct NoteOutOfRange;
+
+impl std::error::Error for NoteOutOfRange {}
+
+/// Parses scientific pitch notation like `C#4`, `Bb3` or `E-1`
+impl FromStr for Note {
+    type Err = ParseNoteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use NoteLetter::*;
+        let mut chars = s.chars();
+        let letter = match chars.next() {
+            Some('C') => C,
+            Some('D') => D,
+            Some('E') => E,
+            Some('F') => F,
+            Some('G') => G,
+            Some('A') => A,
+            Some('B') => B,
+            _ => return Err(ParseNoteError::InvalidLetter),
+        };
+        let rest = chars.as_str();
+        let (accidental, rest) = if let Some(rest) = rest.strip_prefix('#') {
+            (Accidental::Sharp, rest)
+        } else if let Some(rest) = rest.strip_prefix('b') {
+            (Accidental::Flat, rest)
+        } else {
+            (Accidental::None, rest)
+        };
+        let octave: i8 = rest.parse().map_err(|_| ParseNoteError::InvalidOctave)?;
+        if !(-1..=9).contains(&octave) {
+            return Err(ParseNoteError::InvalidOctave);
+        }
+        // octave is -1 based
+        let note = Note::new(letter, accidental, (octave + 1) as u8);
+        note.checked_into_u8().map_err(ParseNoteError::OutOfRange)?;
+        Ok(note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(note: &str) -> u8 {
+        note.parse::<Note>().unwrap().into_u8()
+    }
+
+    #[test]
+    fn accidentals_cross_into_the_neighbouring_octave() {
+        assert_eq!(id("Cb4"), id("B3"));
+        assert_eq!(id("Cb4"), 59);
+        assert_eq!(id("Fb4"), id("E4"));
+        assert_eq!(id("Fb4"), 64);
+        assert_eq!(id("B#3"), id("C4"));
+        assert_eq!(id("B#3"), 60);
+        assert_eq!(id("E#4"), id("F4"));
+        assert_eq!(id("E#4"), 65);
+    }
+
+    #[test]
+    fn notes_outside_of_the_midi_range_are_errors() {
+        use NoteLetter::*;
+        let below = Note::new(C, Accidental::Flat, 0);
+        let above = Note::new(G, Accidental::Sharp, 10);
+        assert_eq!(below.checked_into_u8(), Err(NoteOutOfRange));
+        assert_eq!(above.checked_into_u8(), Err(NoteOutOfRange));
+        assert_eq!((below.into_u8(), above.into_u8()), (0, 127));
+        assert_eq!(
+            Note::new(G, Accidental::None, 10).checked_into_u8(),
+            Ok(127)
+        );
+        assert!(matches!(
+            "Cb-1".parse::<Note>(),
+            Err(ParseNoteError::OutOfRange(NoteOutOfRange))
+        ));
+        assert_eq!(id("C-1"), 0);
+    }
+
+    #[test]
+    fn midi_numbers_round_trip() {
+        for id in 0..=127 {
+            let note = Note::from(id);
+            assert_eq!(note.checked_into_u8(), Ok(id));
+            assert_eq!(note.to_string().parse::<Note>().unwrap().into_u8(), id);
+        }
+    }
+
+    #[test]
+    fn frequency_follows_the_a4_reference() {
+        let a4 = Note::from(69);
+        assert_eq!(a4.freq_with_reference(432.0), 432.0);
+        assert_eq!(a4.freq(), 440.0);
+        let c4 = Note::from(60);
+        let ratio = c4.freq_with_reference(432.0) / c4.freq();
+        assert!((ratio - 432.0 / 440.0).abs() < 1e-12, "{}", ratio);
+    }
+
+    #[test]
+    fn u8_conversion_round_trips() {
+        for id in 0..=127 {
+            assert_eq!(u8::from(Note::from(id)), id);
+        }
+        assert_eq!(u8::from(Note::new(NoteLetter::C, Accidental::Flat, 0)), 0);
+    }
+}
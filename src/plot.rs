@@ -0,0 +1,38 @@
+use crate::noise_maker::{osc, FreqType, WaveType};
+
+/// Draws one period of `wave` at `freq` as ASCII art, with the zero line in the middle
+pub fn waveform(
+    wave: &WaveType,
+    freq: FreqType,
+    width: usize,
+    height: usize,
+    sample_rate: u32,
+) -> String {
+    let width = width.max(1);
+    let height = height.max(2);
+    let zero_row = (height - 1) / 2;
+    let sample_rows = (0..width)
+        .map(|column| {
+            let dt = column as FreqType / (width as FreqType * freq);
+            let sample = osc(dt, freq, wave, 0.0, sample_rate).clamp(-1.0, 1.0);
+            ((1.0 - sample) / 2.0 * (height - 1) as FreqType).round() as usize
+        })
+        .collect::<Vec<_>>();
+    (0..height)
+        .map(|row| {
+            sample_rows
+                .iter()
+                .map(|sample_row| {
+                    if *sample_row == row {
+                        '*'
+                    } else if row == zero_row {
+                        '-'
+                    } else {
+                        ' '
+                    }
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
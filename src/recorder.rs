@@ -0,0 +1,119 @@
+use anyhow::Result;
+use rodio::source::Source;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+/// Passes samples through unchanged while keeping a copy to write to a wav file
+pub struct Recorder<S> {
+    inner: S,
+    handle: RecorderHandle,
+}
+
+/// Shared access to the recorded samples, usable after the `Recorder` is moved into a sink
+#[derive(Clone)]
+pub struct RecorderHandle {
+    samples: Arc<Mutex<Vec<f32>>>,
+    path: PathBuf,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl<S> Recorder<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, path: impl Into<PathBuf>) -> Self {
+        let handle = RecorderHandle::new(path, inner.channels(), inner.sample_rate());
+        Self { inner, handle }
+    }
+
+    /// Appends to an existing recording, for example after reopening the output device
+    pub fn with_handle(inner: S, handle: RecorderHandle) -> Self {
+        Self { inner, handle }
+    }
+
+    pub fn handle(&self) -> RecorderHandle {
+        self.handle.clone()
+    }
+
+    pub fn finalize(&self) -> Result<()> {
+        self.handle.finalize()
+    }
+}
+
+impl RecorderHandle {
+    /// Empty recording, to be filled by a `Recorder` created with `Recorder::with_handle`
+    pub fn new(path: impl Into<PathBuf>, channels: u16, sample_rate: u32) -> Self {
+        Self {
+            samples: Arc::new(Mutex::new(Vec::new())),
+            path: path.into(),
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Records samples that didn't go through a `Recorder`, like a headless render
+    pub fn push_samples(&self, new_samples: &[f32]) {
+        lock_samples(&self.samples).extend_from_slice(new_samples);
+    }
+
+    /// Writes every sample recorded so far as 16-bit PCM
+    pub fn finalize(&self) -> Result<()> {
+        let spec = hound::WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&self.path, spec)?;
+        for sample in lock_samples(&self.samples).iter() {
+            let sample = sample.clamp(-1.0, 1.0) * i16::MAX as f32;
+            writer.write_sample(sample as i16)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+/// The samples are only ever appended, so they are still valid after a panic while recording
+fn lock_samples(samples: &Mutex<Vec<f32>>) -> MutexGuard<'_, Vec<f32>> {
+    samples.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<S> Source for Recorder<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+impl<S> Iterator for Recorder<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next()?;
+        lock_samples(&self.handle.samples).push(sample);
+        Some(sample)
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{
+    noise_maker::{FreqType, NoteEvent},
+    note::Scale,
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NoteDivision {
+    Eighth,
+    Sixteenth,
+}
+
+impl NoteDivision {
+    pub fn steps_per_beat(self) -> FreqType {
+        match self {
+            NoteDivision::Eighth => 2.0,
+            NoteDivision::Sixteenth => 4.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+}
+
+/// Plays the held notes one at a time, driven by the synth clock
+pub struct Arpeggiator {
+    pub bpm: FreqType,
+    pub division: NoteDivision,
+    pub pattern: ArpPattern,
+    /// Number of octaves the held notes are repeated over
+    pub octaves: u8,
+    /// Fraction of a step each note is held for
+    pub gate: FreqType,
+    pub instrument_id: usize,
+    pub velocity: FreqType,
+    sequence: Vec<u8>,
+    step: usize,
+    /// Currently sounding note and when to release it
+    current: Option<(u8, FreqType)>,
+    next_step_time: FreqType,
+}
+
+impl Arpeggiator {
+    pub fn new(bpm: FreqType, division: NoteDivision, pattern: ArpPattern, octaves: u8) -> Self {
+        Self {
+            bpm,
+            division,
+            pattern,
+            octaves: octaves.max(1),
+            gate: 0.8,
+            instrument_id: 0,
+            velocity: 1.0,
+            sequence: Vec::new(),
+            step: 0,
+            current: None,
+            next_step_time: 0.0,
+        }
+    }
+
+    pub fn step_duration(&self) -> FreqType {
+        60.0 / (self.bpm * self.division.steps_per_beat())
+    }
+
+    /// Note sounding until the end of its step
+    pub fn current_note(&self) -> Option<u8> {
+        self.current.map(|(id, _)| id)
+    }
+
+    /// Updates the held notes without restarting the pattern or cutting the current note
+    pub fn set_held_notes(&mut self, notes: &[u8]) {
+        let mut held = notes.to_vec();
+        held.sort_unstable();
+        held.dedup();
+        self.sequence = (0..self.octaves)
+            .flat_map(|octave| held.iter().map(move |id| *id as u16 + 12 * octave as u16))
+            .filter(|id| *id <= 127)
+            .map(|id| id as u8)
+            .collect();
+    }
+
+    /// Returns the note events to send at the synth time `dt`
+    pub fn tick(&mut self, dt: FreqType) -> Vec<NoteEvent> {
+        let mut events = Vec::new();
+        if let Some((id, off_time)) = self.current {
+            if dt >= off_time {
+                events.push(NoteEvent::off(id));
+                self.current = None;
+            }
+        }
+
+        if self.sequence.is_empty() {
+            // Start right away when notes are held again
+            self.next_step_time = dt;
+            return events;
+        }
+
+        if dt >= self.next_step_time {
+            if let Some((id, _)) = self.current.take() {
+                events.push(NoteEvent::off(id));
+            }
+            let id = self.next_note();
+            events.push(NoteEvent::On {
+                id,
+                instrument_id: self.instrument_id,
+                velocity: self.velocity,
+            });
+            let step_duration = self.step_duration();
+            self.current = Some((id, dt + step_duration * self.gate));
+            self.step = self.step.wrapping_add(1);
+            // Scheduled from the previous step to avoid drifting, unless we fell behind
+            self.next_step_time += step_duration;
+            if self.next_step_time <= dt {
+                self.next_step_time = dt + step_duration;
+            }
+        }
+        events
+    }
+
+    /// Releases the current note
+    pub fn stop(&mut self) -> Vec<NoteEvent> {
+        self.sequence.clear();
+        self.current
+            .take()
+            .map(|(id, _)| NoteEvent::off(id))
+            .into_iter()
+            .collect()
+    }
+
+    fn next_note(&self) -> u8 {
+        let len = self.sequence.len();
+        let index = match self.pattern {
+            ArpPattern::Up => self.step % len,
+            ArpPattern::Down => len - 1 - self.step % len,
+            ArpPattern::UpDown if len > 1 => {
+                // Don't repeat the top and bottom notes
+                let period = 2 * len - 2;
+                let position = self.step % period;
+                if position < len {
+                    position
+                } else {
+                    period - position
+                }
+            }
+            ArpPattern::UpDown => 0,
+            ArpPattern::Random => fastrand::usize(..len),
+        };
+        self.sequence[index]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Step {
+    pub id: u8,
+    pub velocity: FreqType,
+}
+
+/// Loops over a pattern of steps, advancing one sixteenth note at a time
+pub struct StepSequencer {
+    pub bpm: FreqType,
+    pub steps: Vec<Option<Step>>,
+    /// Fraction of a step each note is held for
+    pub gate: FreqType,
+    pub instrument_id: usize,
+    position: usize,
+    current: Option<(u8, FreqType)>,
+    next_step_time: Option<FreqType>,
+}
+
+impl StepSequencer {
+    pub fn new(bpm: FreqType, steps: Vec<Option<Step>>) -> Self {
+        Self {
+            bpm,
+            steps,
+            gate: 0.5,
+            instrument_id: 0,
+            position: 0,
+            current: None,
+            next_step_time: None,
+        }
+    }
+
+    pub fn step_duration(&self) -> FreqType {
+        60.0 / (self.bpm * NoteDivision::Sixteenth.steps_per_beat())
+    }
+
+    /// Index of the next step to be played
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the note events to send at the synth time `dt`
+    pub fn tick(&mut self, dt: FreqType) -> Vec<NoteEvent> {
+        let mut events = Vec::new();
+        if let Some((id, off_time)) = self.current {
+            if dt >= off_time {
+                events.push(NoteEvent::off(id));
+                self.current = None;
+            }
+        }
+
+        let next_step_time = *self.next_step_time.get_or_insert(dt);
+        if self.steps.is_empty() || dt < next_step_time {
+            return events;
+        }
+
+        if let Some((id, _)) = self.current.take() {
+            events.push(NoteEvent::off(id));
+        }
+        let step_duration = self.step_duration();
+        self.position %= self.steps.len();
+        if let Some(step) = self.steps[self.position] {
+            events.push(NoteEvent::On {
+                id: step.id,
+                instrument_id: self.instrument_id,
+                velocity: step.velocity,
+            });
+            self.current = Some((step.id, dt + step_duration * self.gate));
+        }
+        self.position = (self.position + 1) % self.steps.len();
+        // Scheduled from the previous step to avoid drifting, unless we fell behind
+        let mut next_step_time = next_step_time + step_duration;
+        if next_step_time <= dt {
+            next_step_time = dt + step_duration;
+        }
+        self.next_step_time = Some(next_step_time);
+        events
+    }
+
+    /// Releases the current note and rewinds to the first step
+    pub fn stop(&mut self) -> Vec<NoteEvent> {
+        self.position = 0;
+        self.next_step_time = None;
+        self.current
+            .take()
+            .map(|(id, _)| NoteEvent::off(id))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Plays a click on every beat, with an accent on the first beat of the bar
+pub struct Metronome {
+    pub bpm: FreqType,
+    pub beats_per_bar: u32,
+    pub instrument_id: usize,
+    pub click_id: u8,
+    pub accent_id: u8,
+    start_time: Option<FreqType>,
+    beat: u64,
+    current: Option<(u8, FreqType)>,
+}
+
+const CLICK_DURATION: FreqType = 0.05;
+
+impl Metronome {
+    pub fn new(bpm: FreqType, beats_per_bar: u32) -> Self {
+        Self {
+            bpm,
+            beats_per_bar: beats_per_bar.max(1),
+            instrument_id: 0,
+            click_id: 84,
+            accent_id: 96,
+            start_time: None,
+            beat: 0,
+            current: None,
+        }
+    }
+
+    pub fn beat_duration(&self) -> FreqType {
+        60.0 / self.bpm
+    }
+
+    /// Returns the note events to send at the synth time `dt`
+    pub fn tick(&mut self, dt: FreqType) -> Vec<NoteEvent> {
+        let mut events = Vec::new();
+        if let Some((id, off_time)) = self.current {
+            if dt >= off_time {
+                events.push(NoteEvent::off(id));
+                self.current = None;
+            }
+        }
+
+        // Beat times are computed from the start instead of accumulated so they never drift
+        let start_time = *self.start_time.get_or_insert(dt);
+        let beat_time = start_time + self.beat as FreqType * self.beat_duration();
+        if dt < beat_time {
+            return events;
+        }
+
+        if let Some((id, _)) = self.current.take() {
+            events.push(NoteEvent::off(id));
+        }
+        let accent = self.beat.is_multiple_of(self.beats_per_bar as u64);
+        let id = if accent {
+            self.accent_id
+        } else {
+            self.click_id
+        };
+        events.push(NoteEvent::On {
+            id,
+            instrument_id: self.instrument_id,
+            velocity: if accent { 1.0 } else { 0.7 },
+        });
+        self.current = Some((id, dt + CLICK_DURATION));
+        // Skip the beats missed while not ticking
+        let elapsed_beats = ((dt - start_time) / self.beat_duration()) as u64;
+        self.beat = elapsed_beats.max(self.beat) + 1;
+        events
+    }
+
+    /// Releases the current click and restarts from the first beat
+    pub fn stop(&mut self) -> Vec<NoteEvent> {
+        self.start_time = None;
+        self.beat = 0;
+        self.current
+            .take()
+            .map(|(id, _)| NoteEvent::off(id))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Timed events running up or down `scale` in the key of `tonic` from `from` to `to`, both
+/// included when they are in the scale. Every note gets an equal part of `duration_secs`.
+/// The events are sorted by time, like the ones of a midi file.
+pub fn glissando(
+    from: u8,
+    to: u8,
+    scale: Scale,
+    tonic: u8,
+    duration_secs: FreqType,
+    instrument_id: usize,
+    velocity: FreqType,
+) -> Vec<(FreqType, NoteEvent)> {
+    let ids = if from <= to {
+        (from..=to).collect::<Vec<_>>()
+    } else {
+        (to..=from).rev().collect()
+    };
+    let ids = ids
+        .into_iter()
+        .filter(|id| scale.contains(*id, tonic))
+        .collect::<Vec<_>>();
+    let note_length = duration_secs / ids.len().max(1) as FreqType;
+    ids.iter()
+        .enumerate()
+        .flat_map(|(index, id)| {
+            let on = index as FreqType * note_length;
+            [
+                (
+                    on,
+                    NoteEvent::On {
+                        id: *id,
+                        instrument_id,
+                        velocity,
+                    },
+                ),
+                (on + note_length, NoteEvent::off(*id)),
+            ]
+        })
+        .collect()
+}
@@ -0,0 +1,93 @@
+use crate::noise_maker::FreqType;
+
+/// Parameter moving linearly toward its target over a short time, avoids zipper noise and clicks
+/// when it is changed while playing
+#[derive(Clone, Copy, Debug)]
+pub struct SmoothedParam {
+    value: FreqType,
+    target: FreqType,
+    step: FreqType,
+    /// Samples to reach the target
+    ramp_samples: u32,
+    remaining: u32,
+}
+
+impl SmoothedParam {
+    /// Short enough to feel instant, long enough not to click
+    pub const DEFAULT_TIME_SECS: FreqType = 0.02;
+
+    pub fn new(value: FreqType, time_secs: FreqType, sample_rate: u32) -> Self {
+        Self {
+            value,
+            target: value,
+            step: 0.0,
+            ramp_samples: (time_secs * sample_rate as FreqType).round().max(1.0) as u32,
+            remaining: 0,
+        }
+    }
+
+    /// Value reached at the end of the ramp
+    pub fn target(&self) -> FreqType {
+        self.target
+    }
+
+    /// Current value, without advancing
+    pub fn value(&self) -> FreqType {
+        self.value
+    }
+
+    pub fn is_smoothing(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Starts a new ramp from the current value
+    pub fn set_target(&mut self, target: FreqType) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        self.remaining = self.ramp_samples;
+        self.step = (target - self.value) / self.ramp_samples as FreqType;
+    }
+
+    /// Advances by one sample and returns the new value
+    pub fn next_sample(&mut self) -> FreqType {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            // Lands exactly on the target despite rounding errors
+            self.value = if self.remaining == 0 {
+                self.target
+            } else {
+                self.value + self.step
+            };
+        }
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaches_the_target_after_the_ramp_time() {
+        // 10ms at 48kHz is 480 samples
+        let mut param = SmoothedParam::new(0.0, 0.01, 48000);
+        param.set_target(1.0);
+        let ramp = (0..480).map(|_| param.next_sample()).collect::<Vec<_>>();
+        assert!(ramp.windows(2).all(|pair| pair[1] > pair[0]));
+        assert!((ramp[239] - 0.5).abs() < 1e-9);
+        assert!(ramp[478] < 1.0);
+        assert_eq!(ramp[479], 1.0);
+        assert!(!param.is_smoothing());
+        assert_eq!(param.next_sample(), 1.0);
+
+        // Changed halfway, the new ramp starts where the value is
+        param.set_target(0.0);
+        for _ in 0..240 {
+            param.next_sample();
+        }
+        param.set_target(1.0);
+        assert!(param.next_sample() > 0.5);
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{
+    instruments::{Instrument, InstrumentType},
+    noise_maker::NoiseMakerStatus,
+    note::Note,
+};
+use std::fmt::Write;
+
+/// Table of the playing notes with their name, frequency, envelope amplitude and instrument,
+/// one note per line after a header line
+pub fn notes_table(status: &NoiseMakerStatus, instruments: &[InstrumentType]) -> String {
+    let mut table = format!(
+        "{:<6}{:>12}{:>11}  {}",
+        "Note", "Frequency", "Amplitude", "Instrument"
+    );
+    for note in status.notes.iter() {
+        let instrument = instruments
+            .get(note.instrument_id)
+            .map(|instrument| instrument.name())
+            .unwrap_or("?");
+        // Writing to a String can't fail
+        let _ = write!(
+            table,
+            "\n{:<6}{:>9.2} Hz{:>11.2}  {}",
+            Note::from(note.id).to_string(),
+            note.freq,
+            note.amplitude,
+            instrument
+        );
+    }
+    table
+}
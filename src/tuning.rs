@@ -0,0 +1,115 @@
+use crate::{noise_maker::FreqType, note::Note};
+use enum_dispatch::enum_dispatch;
+
+#[enum_dispatch]
+pub trait Tuning {
+    /// Frequency in Hz of the midi note `id`
+    fn id_to_freq(&self, id: u8) -> FreqType;
+}
+
+#[enum_dispatch(Tuning)]
+pub enum TuningType {
+    EqualTemperament,
+    JustIntonation,
+    EqualDivisionsOfOctave,
+}
+
+/// Every semitone has the same frequency ratio, the usual tuning
+pub struct EqualTemperament {
+    pub a4_hz: FreqType,
+}
+
+impl Default for EqualTemperament {
+    fn default() -> Self {
+        Self { a4_hz: 440.0 }
+    }
+}
+
+impl Tuning for EqualTemperament {
+    fn id_to_freq(&self, id: u8) -> FreqType {
+        if self.a4_hz == 440.0 {
+            Note::from(id).freq()
+        } else {
+            Note::from(id).freq_with_reference(self.a4_hz)
+        }
+    }
+}
+
+/// Ratios of the 5-limit just intonation scale, from the tonic to its major seventh
+const JUST_RATIOS: [FreqType; 12] = [
+    1.0,
+    16.0 / 15.0,
+    9.0 / 8.0,
+    6.0 / 5.0,
+    5.0 / 4.0,
+    4.0 / 3.0,
+    45.0 / 32.0,
+    3.0 / 2.0,
+    8.0 / 5.0,
+    5.0 / 3.0,
+    9.0 / 5.0,
+    15.0 / 8.0,
+];
+
+/// Intervals are whole number ratios from the tonic, so chords in its key beat less
+pub struct JustIntonation {
+    /// Tuned like equal temperament, the other notes are tuned relative to it
+    pub tonic: u8,
+}
+
+impl JustIntonation {
+    pub fn new(tonic: u8) -> Self {
+        Self { tonic }
+    }
+}
+
+impl Tuning for JustIntonation {
+    fn id_to_freq(&self, id: u8) -> FreqType {
+        let interval = id as i16 - self.tonic as i16;
+        let octaves = interval.div_euclid(12);
+        let ratio = JUST_RATIOS[interval.rem_euclid(12) as usize];
+        Note::from(self.tonic).freq() * ratio * 2.0_f64.powi(octaves as i32)
+    }
+}
+
+/// Microtonal tuning dividing the octave in this many equal steps, like 24 for quarter tones.
+/// Note ids are steps from A4, which stays at 440Hz
+pub struct EqualDivisionsOfOctave(pub u16);
+
+impl Tuning for EqualDivisionsOfOctave {
+    fn id_to_freq(&self, id: u8) -> FreqType {
+        // A4 is the midi note 69
+        440.0 * 2.0_f64.powf((id as FreqType - 69.0) / self.0.max(1) as FreqType)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn just_fifth_is_three_halves() {
+        // From C4, the tonic, and from C3 below it
+        let tuning = JustIntonation::new(60);
+        for tonic in [60, 48] {
+            let ratio = tuning.id_to_freq(tonic + 7) / tuning.id_to_freq(tonic);
+            assert!((ratio - 1.5).abs() < 1e-12, "{}", ratio);
+        }
+        assert_eq!(
+            tuning.id_to_freq(60),
+            EqualTemperament::default().id_to_freq(60)
+        );
+    }
+
+    #[test]
+    fn quarter_tone_is_halfway_between_two_semitones() {
+        let quarter_tones = EqualDivisionsOfOctave(24);
+        let semitones = EqualTemperament::default();
+        assert_eq!(quarter_tones.id_to_freq(69), 440.0);
+        // One quarter tone above A4 is the geometric mean of A4 and A#4
+        let midpoint = (semitones.id_to_freq(69) * semitones.id_to_freq(70)).sqrt();
+        let quarter_tone = quarter_tones.id_to_freq(70);
+        assert!((quarter_tone - midpoint).abs() < 1e-9, "{}", quarter_tone);
+        assert!((quarter_tones.id_to_freq(71) - semitones.id_to_freq(70)).abs() < 1e-9);
+    }
+}